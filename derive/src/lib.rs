@@ -0,0 +1,320 @@
+//! `#[derive(IOAble)]` for the binary protocol of jell_machine_server.
+//!
+//! Structs read and write their fields in declaration order. Enums need
+//! `#[repr(u8)]` and an explicit discriminant on every variant, which is
+//! written as a one byte tag before the fields of the variant.
+//!
+//! Container attributes:
+//! - `#[io(validate = "path")]` calls `path(&value) -> Result<(), DecodeErrorKind>`
+//!   after a value was read, errors point at the start of the value.
+//! - `#[io(tag = "message tag")]` names the tag of an enum in decode errors,
+//!   `tag` if not given.
+//!
+//! Variant attributes:
+//! - `#[io(skip)]` leaves a variant out of the encoding: its tag is read as
+//!   an unknown tag, it is not in the schema and writing it panics.
+//!
+//! Field attributes:
+//! - `#[io(name = "x")]` names a tuple field in decode errors.
+//! - `#[io(with = "module")]` uses `module::read_from` and `module::write_to`
+//!   instead of the `IOAble` impl of the field type, and `module::describe`
+//!   for the schema.
+//!
+//! Besides `IOAble` this also implements `schema::Describe`, doc comments
+//! on the type, its variants and fields end up in the schema.
+
+use proc_macro2::{TokenStream, Span};
+use quote::{quote, format_ident};
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, Field, Fields, Error, Ident, Lit, LitStr, Meta, Path, Expr, Type, Variant, spanned::Spanned};
+
+#[proc_macro_derive(IOAble, attributes(io))]
+pub fn derive_ioable(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let (validate, tag_name) = container_attrs(&input)?;
+
+    let doc = doc(&input.attrs);
+    let name_str = name.to_string();
+
+    let (read, write, definition) = match &input.data {
+        Data::Struct(data) => {
+            let (read, write) = expand_struct(&data.fields)?;
+            let fields = FieldInfo::collect(&data.fields)?;
+            let fields = fields.iter().map(FieldInfo::describe);
+            let definition = quote! {
+                crate::schema::Definition::Struct { name: #name_str, doc: #doc, fields: vec![#(#fields),*] }
+            };
+            (read, write, definition)
+        }
+        Data::Enum(data) => {
+            check_repr(&input)?;
+            let mut read_arms = Vec::new();
+            let mut write_arms = Vec::new();
+            let mut variants = Vec::new();
+            for variant in &data.variants {
+                let ident = &variant.ident;
+                let (_, tag) = variant.discriminant.as_ref()
+                    .ok_or_else(|| Error::new(variant.span(), "IOAble enums need an explicit discriminant on every variant"))?;
+                if skipped(variant)? {
+                    let message = format!("{}::{} has no encoding here", name, ident);
+                    write_arms.push(quote!(Self::#ident { .. } => ::core::panic!(#message),));
+                    continue;
+                }
+                let fields = FieldInfo::collect(&variant.fields)?;
+                let context = ident.to_string();
+                let reads = fields.iter().map(|f| f.read(Some(&context)));
+                let writes = fields.iter().map(|f| f.write());
+                let construct = construct(quote!(Self::#ident), &variant.fields);
+
+                read_arms.push(quote! {
+                    tag if tag == (#tag) => {
+                        #(#reads)*
+                        ::core::result::Result::Ok(#construct)
+                    }
+                });
+                write_arms.push(quote! {
+                    #construct => {
+                        stream.write_byte(#tag);
+                        #(#writes)*
+                    }
+                });
+
+                let variant_doc = self::doc(&variant.attrs);
+                let describes = fields.iter().map(FieldInfo::describe);
+                variants.push(quote! {
+                    crate::schema::Variant { name: #context, tag: #tag, doc: #variant_doc, fields: vec![#(#describes),*] }
+                });
+            }
+
+            let read = quote! {
+                let start = stream.position();
+                match stream.read::<u8>().context(#tag_name)? {
+                    #(#read_arms)*
+                    tag => ::core::result::Result::Err(crate::binary_io::DecodeError::new(start, crate::binary_io::DecodeErrorKind::UnknownTag(tag))).context(#tag_name),
+                }
+            };
+            let write = quote! {
+                match self {
+                    #(#write_arms)*
+                }
+            };
+            let definition = quote! {
+                crate::schema::Definition::Enum { name: #name_str, doc: #doc, variants: vec![#(#variants),*] }
+            };
+            (read, write, definition)
+        }
+        Data::Union(_) => return Err(Error::new(Span::call_site(), "IOAble cannot be derived for unions")),
+    };
+
+    let read = match validate {
+        Some(validate) => quote! {
+            let start = stream.position();
+            let value: Self = (|| { #read })()?;
+            #validate(&value).map_err(|kind| crate::binary_io::DecodeError::new(start, kind))?;
+            ::core::result::Result::Ok(value)
+        },
+        None => read,
+    };
+
+    Ok(quote! {
+        impl #impl_generics crate::binary_io::IOAble for #name #ty_generics #where_clause {
+            #[allow(unused_imports)]
+            fn read_from(stream: &mut crate::binary_io::InputStream) -> ::core::result::Result<Self, crate::binary_io::DecodeError> {
+                use crate::binary_io::Context;
+                #read
+            }
+
+            #[allow(unused_variables)]
+            fn write_to(&self, stream: &mut crate::binary_io::OutputStream) {
+                #write
+            }
+        }
+
+        impl #impl_generics crate::schema::Describe for #name #ty_generics #where_clause {
+            fn describe(schema: &mut crate::schema::Schema) -> crate::schema::Type {
+                if schema.start(#name_str) {
+                    let definition = #definition;
+                    schema.define(definition);
+                }
+                crate::schema::Type::Ref { name: #name_str }
+            }
+        }
+    })
+}
+
+fn expand_struct(fields: &Fields) -> syn::Result<(TokenStream, TokenStream)> {
+    let infos = FieldInfo::collect(fields)?;
+    let reads = infos.iter().map(|f| f.read(None));
+    let writes = infos.iter().map(|f| f.write());
+    let construct = construct(quote!(Self), fields);
+
+    let read = quote! {
+        #(#reads)*
+        ::core::result::Result::Ok(#construct)
+    };
+    let write = quote! {
+        let #construct = self;
+        #(#writes)*
+    };
+    Ok((read, write))
+}
+
+/// Builds `path { a: field_a, .. }`, `path(field_0, ..)` or `path`, usable as expression and pattern.
+fn construct(path: TokenStream, fields: &Fields) -> TokenStream {
+    let bindings = fields.iter().enumerate().map(|(i, field)| binding(i, field));
+    match fields {
+        Fields::Named(named) => {
+            let members = named.named.iter().map(|f| f.ident.as_ref().unwrap());
+            quote!(#path { #(#members: #bindings),* })
+        }
+        Fields::Unnamed(_) => quote!(#path(#(#bindings),*)),
+        Fields::Unit => quote!(#path),
+    }
+}
+
+fn binding(index: usize, field: &Field) -> Ident {
+    match &field.ident {
+        Some(ident) => format_ident!("field_{}", ident),
+        None => format_ident!("field_{}", index),
+    }
+}
+
+struct FieldInfo {
+    binding: Ident,
+    context: String,
+    with: Option<Path>,
+    ty: Type,
+    doc: String,
+}
+
+impl FieldInfo {
+    fn collect(fields: &Fields) -> syn::Result<Vec<Self>> {
+        fields.iter().enumerate().map(|(i, field)| {
+            let mut context = field.ident.as_ref().map(|ident| ident.to_string()).unwrap_or_else(|| i.to_string());
+            let mut with = None;
+            for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("io")) {
+                attr.parse_nested_meta(|meta| {
+                    if meta.path.is_ident("name") {
+                        context = meta.value()?.parse::<LitStr>()?.value();
+                        Ok(())
+                    }
+                    else if meta.path.is_ident("with") {
+                        with = Some(meta.value()?.parse::<LitStr>()?.parse::<Path>()?);
+                        Ok(())
+                    }
+                    else {
+                        Err(meta.error("expected `name` or `with`"))
+                    }
+                })?;
+            }
+            Ok(FieldInfo {
+                binding: binding(i, field),
+                context,
+                with,
+                ty: field.ty.clone(),
+                doc: doc(&field.attrs),
+            })
+        }).collect()
+    }
+
+    fn read(&self, variant: Option<&str>) -> TokenStream {
+        let binding = &self.binding;
+        let context = &self.context;
+        let variant = variant.map(|variant| quote!(.context(#variant)));
+        match &self.with {
+            Some(with) => quote!(let #binding = #with::read_from(stream).context(#context)#variant?;),
+            None => quote!(let #binding = stream.read().context(#context)#variant?;),
+        }
+    }
+
+    fn write(&self) -> TokenStream {
+        let binding = &self.binding;
+        match &self.with {
+            Some(with) => quote!(#with::write_to(#binding, stream);),
+            None => quote!(crate::binary_io::IOAble::write_to(#binding, stream);),
+        }
+    }
+
+    fn describe(&self) -> TokenStream {
+        let name = &self.context;
+        let doc = &self.doc;
+        let ty = match &self.with {
+            Some(with) => quote!(#with::describe(schema)),
+            None => {
+                let ty = &self.ty;
+                quote!(<#ty as crate::schema::Describe>::describe(schema))
+            }
+        };
+        quote!(crate::schema::Field { name: #name, ty: #ty, doc: #doc })
+    }
+}
+
+/// The `///` comments of an item, one line per line.
+fn doc(attrs: &[Attribute]) -> String {
+    let lines = attrs.iter().filter_map(|attr| match &attr.meta {
+        Meta::NameValue(meta) if meta.path.is_ident("doc") => match &meta.value {
+            Expr::Lit(lit) => match &lit.lit {
+                Lit::Str(text) => Some(text.value().trim().to_string()),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    });
+    lines.collect::<Vec<_>>().join("\n")
+}
+
+fn skipped(variant: &Variant) -> syn::Result<bool> {
+    let mut skip = false;
+    for attr in variant.attrs.iter().filter(|attr| attr.path().is_ident("io")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                skip = true;
+                Ok(())
+            }
+            else {
+                Err(meta.error("expected `skip`"))
+            }
+        })?;
+    }
+    Ok(skip)
+}
+
+fn container_attrs(input: &DeriveInput) -> syn::Result<(Option<Path>, String)> {
+    let mut validate = None;
+    let mut tag = "tag".to_string();
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("io")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("validate") {
+                validate = Some(meta.value()?.parse::<LitStr>()?.parse::<Path>()?);
+                Ok(())
+            }
+            else if meta.path.is_ident("tag") {
+                tag = meta.value()?.parse::<LitStr>()?.value();
+                Ok(())
+            }
+            else {
+                Err(meta.error("expected `validate` or `tag`"))
+            }
+        })?;
+    }
+    Ok((validate, tag))
+}
+
+fn check_repr(input: &DeriveInput) -> syn::Result<()> {
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("repr")) {
+        let repr: Expr = attr.parse_args()?;
+        if let Expr::Path(path) = repr {
+            if path.path.is_ident("u8") {
+                return Ok(());
+            }
+        }
+    }
+    Err(Error::new(input.ident.span(), "IOAble enums need #[repr(u8)]"))
+}
@@ -0,0 +1,8 @@
+#![no_main]
+
+use jell_machine_server::cellformat::parse;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse(data.to_vec());
+});
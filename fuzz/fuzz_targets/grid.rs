@@ -0,0 +1,13 @@
+#![no_main]
+
+use jell_machine_server::{binary_io::{InputStream, OutputStream}, grid::Grid};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(grid) = InputStream::new(data).read::<Grid>() else { return };
+    assert_eq!(grid.cells.len(), grid.width as usize * grid.height as usize);
+
+    let mut stream = OutputStream::new();
+    stream.write(&grid);
+    assert_eq!(InputStream::new(&stream.bytes).read::<Grid>().as_ref(), Ok(&grid));
+});
@@ -0,0 +1,19 @@
+#![no_main]
+
+use jell_machine_server::{binary_io::{InputStream, OutputStream}, messages::JMMessage};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(message) = JMMessage::parse_v1(&mut InputStream::new(data)) else { return };
+
+    // decoding normalizes some values (bools, directions of empty cells),
+    // so only the second round trip has to be exact
+    let mut first = OutputStream::new();
+    message.write_v1(&mut first);
+    let reparsed = JMMessage::parse_v1(&mut InputStream::new(&first.bytes)).expect("encoded message does not decode");
+    assert_eq!(reparsed, message);
+
+    let mut second = OutputStream::new();
+    reparsed.write_v1(&mut second);
+    assert_eq!(first.bytes, second.bytes);
+});
@@ -0,0 +1,20 @@
+#![no_main]
+
+use jell_machine_server::{binary_io::{InputStream, OutputStream}, messages::JMMessage, protocol::Protocol};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // the first byte picks the compression
+    let Some((&which, data)) = data.split_first() else { return };
+    let protocol = Protocol::parse(["2", "2.deflate", "2.zstd"][which as usize % 3]).unwrap();
+    let Ok(message) = JMMessage::parse(&mut InputStream::new(data), protocol) else { return };
+
+    let mut first = OutputStream::new();
+    message.write(&mut first, protocol);
+    let reparsed = JMMessage::parse(&mut InputStream::new(&first.bytes), protocol).expect("encoded message does not decode");
+    assert_eq!(reparsed, message);
+
+    let mut second = OutputStream::new();
+    reparsed.write(&mut second, protocol);
+    assert_eq!(first.bytes, second.bytes);
+});
@@ -0,0 +1,296 @@
+use std::{collections::VecDeque, fmt, fs::{File, OpenOptions}, io::{self, BufRead, BufReader, BufWriter, Write}, net::SocketAddr, path::{Path, PathBuf}, time::{Duration, SystemTime}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{log::{LogRecord, Level, Category, Logger}, time::timestamp, grid_view::direction_name, writer::{Writer, Sink}};
+
+/// Most matching changes `/audit` shows, the newest ones.
+pub const MAX_SHOWN: usize = 20;
+
+pub const AUDIT_USAGE: &str = "Usage: /audit [region x y [x2 y2]] [client <id>] [nick <nickname>] [since <time>] [before <time>] [last <30s|10m|2h|1d>], times are UTC like 14:05, 2024-05-01 or 2024-05-01T14:05";
+
+/// One applied cell change, a line of JSON in the audit file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// UTC, `YYYY-MM-DD HH:MM:SS`, so entries compare by time as strings.
+    pub time: String,
+    pub client: String,
+    pub nickname: Option<String>,
+    pub addr: SocketAddr,
+    pub x: u16,
+    pub y: u16,
+    pub old: Option<(String, u8)>,
+    pub new: Option<(String, u8)>,
+}
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cell = |cell: &Option<(String, u8)>| match cell {
+            Some((id, direction)) => format!("{} {}", id, direction_name(*direction)),
+            None => "empty".to_string(),
+        };
+        let who = match &self.nickname {
+            Some(nickname) => format!("{} ({}, {})", nickname, self.client, self.addr),
+            None => format!("{} ({})", self.client, self.addr),
+        };
+        write!(f, "{} {} set {} {}: {} -> {}", self.time, who, self.x, self.y, cell(&self.old), cell(&self.new))
+    }
+}
+
+pub fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Appends grid changes to the audit file. Entries are queued and written by
+/// a thread of their own, which flushes the file once it has caught up with them.
+/// None are dropped as long as `finish` is awaited before exiting.
+#[derive(Clone)]
+pub struct Audit {
+    writer: Writer<AuditEntry>,
+    path: PathBuf,
+}
+
+impl Audit {
+    pub fn new(path: PathBuf, file: File, log: Logger) -> Self {
+        let writer = Writer::new(AuditFile(BufWriter::new(file)), move |e| {
+            log.log(LogRecord::new(Level::Error, Category::Server, None, format!("Error writing audit log: {}", e)));
+        });
+        Self { writer, path }
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        self.writer.send(entry);
+    }
+
+    /// Stops taking entries and waits until the queued ones are written.
+    pub async fn finish(&self) {
+        self.writer.finish().await;
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Entries as lines of JSON.
+struct AuditFile(BufWriter<File>);
+
+impl Sink<AuditEntry> for AuditFile {
+    fn write(&mut self, entry: AuditEntry) -> io::Result<()> {
+        writeln!(self.0, "{}", serde_json::to_string(&entry).unwrap())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// What `/audit` looks for, everything that is given has to match.
+#[derive(Debug, Default, PartialEq)]
+pub struct Query {
+    /// Inclusive corners, left top and right bottom.
+    pub region: Option<((u16, u16), (u16, u16))>,
+    /// Matches ids exactly, they are unique unlike nicknames of past clients.
+    pub client: Option<String>,
+    pub nickname: Option<String>,
+    pub since: Option<String>,
+    pub before: Option<String>,
+}
+
+impl Query {
+    /// Reads the arguments of `/audit`, `now` is for relative times.
+    pub fn parse(args: &[&str], now: SystemTime) -> Result<Self, &'static str> {
+        let mut query = Query::default();
+        let mut args = args.iter().copied().peekable();
+        let number = |arg: Option<&str>| arg.and_then(|arg| arg.parse::<u16>().ok()).ok_or(AUDIT_USAGE);
+        while let Some(arg) = args.next() {
+            match arg {
+                "region" => {
+                    let start = (number(args.next())?, number(args.next())?);
+                    let end = match args.peek().and_then(|arg| arg.parse::<u16>().ok()) {
+                        Some(x2) => {
+                            args.next();
+                            (x2, number(args.next())?)
+                        }
+                        None => start,
+                    };
+                    query.region = Some(((start.0.min(end.0), start.1.min(end.1)), (start.0.max(end.0), start.1.max(end.1))));
+                }
+                "client" => query.client = Some(args.next().ok_or(AUDIT_USAGE)?.to_string()),
+                "nick" => query.nickname = Some(args.next().ok_or(AUDIT_USAGE)?.to_string()),
+                "since" => query.since = Some(parse_time(args.next().ok_or(AUDIT_USAGE)?, now)?),
+                "before" => query.before = Some(parse_time(args.next().ok_or(AUDIT_USAGE)?, now)?),
+                "last" => {
+                    let duration = parse_duration(args.next().ok_or(AUDIT_USAGE)?)?;
+                    query.since = Some(timestamp(now.checked_sub(duration).unwrap_or(SystemTime::UNIX_EPOCH)));
+                }
+                _ => return Err(AUDIT_USAGE),
+            }
+        }
+        Ok(query)
+    }
+
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        let in_region = self.region.is_none_or(|((x1, y1), (x2, y2))| (x1..=x2).contains(&entry.x) && (y1..=y2).contains(&entry.y));
+        let by_client = self.client.as_deref().is_none_or(|id| entry.client == id);
+        let by_nickname = self.nickname.as_ref().is_none_or(|nickname| entry.nickname.as_ref() == Some(nickname));
+        // a time given as a prefix, like a day, compares as its start
+        let after_since = self.since.as_deref().is_none_or(|since| entry.time.as_str() >= since);
+        let before_end = self.before.as_deref().is_none_or(|before| entry.time.as_str() < before);
+        in_region && by_client && by_nickname && after_since && before_end
+    }
+}
+
+/// Goes through the audit file line by line, returns how many changes match
+/// and the newest `MAX_SHOWN` of them. Lines that can't be read are skipped.
+pub fn search(path: &Path, query: &Query) -> io::Result<(usize, Vec<AuditEntry>)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, Vec::new())),
+        Err(e) => return Err(e),
+    };
+    let mut count = 0;
+    let mut newest = VecDeque::with_capacity(MAX_SHOWN);
+    let mut lines = BufReader::new(file).split(b'\n');
+    while let Some(line) = lines.next().transpose()? {
+        let Ok(entry) = serde_json::from_slice::<AuditEntry>(&line) else { continue };
+        if !query.matches(&entry) {
+            continue;
+        }
+        count += 1;
+        if newest.len() == MAX_SHOWN {
+            newest.pop_front();
+        }
+        newest.push_back(entry);
+    }
+    Ok((count, newest.into()))
+}
+
+/// `HH:MM[:SS]` today, `YYYY-MM-DD` or `YYYY-MM-DDTHH[:MM[:SS]]`, all UTC.
+fn parse_time(time: &str, now: SystemTime) -> Result<String, &'static str> {
+    let time = match time.len() {
+        5 | 8 => format!("{} {}", &timestamp(now)[..10], time),
+        _ => time.replacen('T', " ", 1),
+    };
+    let template = "0000-00-00 00:00:00";
+    let valid = [10, 13, 16, 19].contains(&time.len())
+        && time.chars().zip(template.chars()).all(|(ch, expected)| match expected {
+            '0' => ch.is_ascii_digit(),
+            _ => ch == expected,
+        });
+    match valid {
+        true => Ok(time),
+        false => Err(AUDIT_USAGE),
+    }
+}
+
+fn parse_duration(duration: &str) -> Result<Duration, &'static str> {
+    if !duration.is_ascii() {
+        return Err(AUDIT_USAGE);
+    }
+    let split = duration.len().saturating_sub(1);
+    let amount: u64 = duration[..split].parse().map_err(|_| AUDIT_USAGE)?;
+    let unit = match &duration[split..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(AUDIT_USAGE),
+    };
+    Ok(Duration::from_secs(amount.saturating_mul(unit)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::UNIX_EPOCH};
+
+    use super::*;
+
+    fn entry(time: &str, client: &str, x: u16, y: u16) -> AuditEntry {
+        AuditEntry {
+            time: time.to_string(),
+            client: client.to_string(),
+            nickname: Some(format!("{}-nick", client)),
+            addr: "127.0.0.1:4000".parse().unwrap(),
+            x,
+            y,
+            old: None,
+            new: Some(("mover".to_string(), 1)),
+        }
+    }
+
+    // 2024-12-31 23:59:59
+    fn now() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_735_689_599)
+    }
+
+    #[test]
+    fn parsing() {
+        let query = Query::parse(&["region", "5", "2", "1", "4", "client", "a", "since", "12:30"], now()).unwrap();
+        assert_eq!(query.region, Some(((1, 2), (5, 4))));
+        assert_eq!(query.client.as_deref(), Some("a"));
+        assert_eq!(query.since.as_deref(), Some("2024-12-31 12:30"));
+
+        let query = Query::parse(&["last", "2h", "before", "2024-12-31T23:00:05", "region", "3", "3"], now()).unwrap();
+        assert_eq!(query.since.as_deref(), Some("2024-12-31 21:59:59"));
+        assert_eq!(query.before.as_deref(), Some("2024-12-31 23:00:05"));
+        assert_eq!(query.region, Some(((3, 3), (3, 3))));
+        assert_eq!(Query::parse(&[], now()), Ok(Query::default()));
+
+        for args in [&["region", "1"][..], &["since", "yesterday"], &["last", "5w"], &["before", "2024-1-1"], &["nope"]] {
+            assert_eq!(Query::parse(args, now()), Err(AUDIT_USAGE));
+        }
+    }
+
+    #[test]
+    fn matching() {
+        let query = Query::parse(&["region", "0", "0", "9", "9", "since", "2024-12-31", "before", "2024-12-31T12"], now()).unwrap();
+        assert!(query.matches(&entry("2024-12-31 00:00:00", "a", 9, 0)));
+        assert!(query.matches(&entry("2024-12-31 11:59:59", "a", 0, 9)));
+        assert!(!query.matches(&entry("2024-12-30 23:59:59", "a", 0, 0)));
+        assert!(!query.matches(&entry("2024-12-31 12:00:00", "a", 0, 0)));
+        assert!(!query.matches(&entry("2024-12-31 01:00:00", "a", 10, 0)));
+
+        let query = Query::parse(&["client", "b"], now()).unwrap();
+        assert!(query.matches(&entry("2024-12-31 01:00:00", "b", 0, 0)));
+        assert!(!query.matches(&entry("2024-12-31 01:00:00", "a", 0, 0)));
+        // ids are not nicknames, nicknames are asked for with nick
+        let query = Query::parse(&["client", "b-nick"], now()).unwrap();
+        assert!(!query.matches(&entry("2024-12-31 01:00:00", "b", 0, 0)));
+        let query = Query::parse(&["nick", "b-nick"], now()).unwrap();
+        assert!(query.matches(&entry("2024-12-31 01:00:00", "b", 0, 0)));
+        assert!(!query.matches(&entry("2024-12-31 01:00:00", "b-nick", 0, 0)));
+    }
+
+    #[test]
+    fn searching() {
+        let path = std::env::temp_dir().join(format!("jell-machine-audit-{:x}", rand::random::<u64>()));
+        let mut lines: Vec<_> = (0..30).map(|i| serde_json::to_string(&entry("2024-12-31 01:00:00", "a", i, i % 2)).unwrap()).collect();
+        lines.insert(3, "not json".to_string());
+        fs::write(&path, lines.join("\n")).unwrap();
+
+        let (count, shown) = search(&path, &Query::parse(&["region", "0", "1", "100", "1"], now()).unwrap()).unwrap();
+        assert_eq!(count, 15);
+        assert_eq!(shown.len(), 15);
+        let (count, shown) = search(&path, &Query::default()).unwrap();
+        assert_eq!((count, shown.len(), shown[0].x), (30, MAX_SHOWN, 10));
+        assert_eq!(shown[0].to_string(), "2024-12-31 01:00:00 a-nick (a, 127.0.0.1:4000) set 10 0: empty -> mover down");
+        fs::remove_file(&path).unwrap();
+        assert_eq!(search(&path, &Query::default()).unwrap().0, 0);
+    }
+
+    #[tokio::test]
+    async fn finishing_writes_everything() {
+        let path = std::env::temp_dir().join(format!("jell-machine-audit-{:x}", rand::random::<u64>()));
+        let audit = Audit::new(path.clone(), open(&path).unwrap(), Logger::new(async_channel::bounded(1).0));
+        for x in 0..1000 {
+            audit.record(entry("2024-12-31 01:00:00", "a", x, 0));
+        }
+        audit.finish().await;
+        audit.record(entry("2024-12-31 01:00:00", "a", 0, 0));
+        audit.finish().await;
+
+        assert_eq!(search(&path, &Query::default()).unwrap().0, 1000);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,27 +1,214 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    UnexpectedEof { needed: usize, remaining: usize },
+    InvalidUtf8,
+    NegativeLength(i32),
+    UnknownTag(u8),
+    /// A length that is larger than the decode limits allow.
+    TooLong { length: u64, max: u64 },
+    /// A value that is well-formed but not allowed.
+    Invalid(&'static str),
+    /// The type can only be written, not read.
+    WriteOnly,
+    /// Text that is not valid JSON for the expected type.
+    Json(String),
+}
+
+impl fmt::Display for DecodeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeErrorKind::UnexpectedEof { needed, remaining } =>
+                write!(f, "unexpected end of message, needed {} bytes but only {} left", needed, remaining),
+            DecodeErrorKind::InvalidUtf8 => write!(f, "invalid UTF-8 in string"),
+            DecodeErrorKind::NegativeLength(length) => write!(f, "negative length {}", length),
+            DecodeErrorKind::UnknownTag(tag) => write!(f, "unknown tag {}", tag),
+            DecodeErrorKind::TooLong { length, max } => write!(f, "length {} exceeds the limit of {}", length, max),
+            DecodeErrorKind::Invalid(reason) => write!(f, "{}", reason),
+            DecodeErrorKind::WriteOnly => write!(f, "type cannot be decoded"),
+            DecodeErrorKind::Json(message) => write!(f, "invalid JSON: {}", message),
+        }
+    }
+}
+
+/// Why and where decoding failed. `context` lists what was being read,
+/// innermost first, e.g. `["id", "SetCell"]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub offset: usize,
+    pub kind: DecodeErrorKind,
+    pub context: Vec<&'static str>,
+}
+
+impl DecodeError {
+    pub fn new(offset: usize, kind: DecodeErrorKind) -> Self {
+        Self { offset, kind, context: Vec::new() }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.kind, self.offset)?;
+        if !self.context.is_empty() {
+            let path = self.context.iter().rev().copied().collect::<Vec<_>>().join(".");
+            write!(f, " while reading {}", path)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub trait Context<T> {
+    /// Adds the name of what was being read to the error.
+    fn context(self, context: &'static str) -> Result<T, DecodeError>;
+}
+
+impl<T> Context<T> for Result<T, DecodeError> {
+    fn context(self, context: &'static str) -> Result<T, DecodeError> {
+        self.map_err(|mut e| {
+            e.context.push(context);
+            e
+        })
+    }
+}
+
+/// Upper bounds for lengths read from untrusted input, so a few bytes
+/// can never make the decoder allocate gigabytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    pub max_string_length: usize,
+    pub max_collection_length: usize,
+    pub max_message_size: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_string_length: 1024,
+            max_collection_length: 1 << 20,
+            max_message_size: 16 << 20,
+        }
+    }
+}
+
+/// Reads values from a borrowed buffer, keeping track of the current position.
 #[derive(Clone)]
-pub struct InputStream {
-    pub bytes: Vec<u8>,
+pub struct InputStream<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    limits: DecodeLimits,
 }
 
-impl InputStream {
-    pub fn new(bytes: Vec<u8>) -> Self {
+impl<'a> InputStream<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self::with_limits(bytes, DecodeLimits::default())
+    }
+
+    pub fn with_limits(bytes: &'a [u8], limits: DecodeLimits) -> Self {
         InputStream {
-            bytes
+            bytes,
+            position: 0,
+            limits,
         }
     }
 
     #[inline(always)]
-    pub fn read_byte(&mut self) -> Option<u8> {
-        if self.bytes.is_empty() { None }
-        else { Some(self.bytes.remove(0)) }
+    pub fn limits(&self) -> &DecodeLimits {
+        &self.limits
+    }
+
+    /// Fails if the whole input is larger than `max_message_size`.
+    pub fn check_message_size(&self) -> Result<(), DecodeError> {
+        check_length(0, self.bytes.len() as u64, self.limits.max_message_size)
+    }
+
+    /// Checks a collection length that was read at `offset` against `max_collection_length`.
+    pub fn check_collection_length(&self, offset: usize, length: u64) -> Result<usize, DecodeError> {
+        check_length(offset, length, self.limits.max_collection_length)?;
+        Ok(length as usize)
+    }
+
+    /// Checks a string length in bytes that was read at `offset` against `max_string_length`.
+    pub fn check_string_length(&self, offset: usize, length: u64) -> Result<usize, DecodeError> {
+        check_length(offset, length, self.limits.max_string_length)?;
+        Ok(length as usize)
+    }
+
+    /// Capacity to reserve for `length` elements of at least `min_size` bytes each,
+    /// never more than the remaining input could hold.
+    #[inline(always)]
+    pub fn capacity_for(&self, length: usize, min_size: usize) -> usize {
+        length.min(self.remaining() / min_size.max(1))
     }
 
     #[inline(always)]
-    pub fn read<T>(&mut self) -> Option<T> where T: IOAble {
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    #[inline(always)]
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    /// Creates an error at the current position.
+    pub fn error(&self, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError::new(self.position, kind)
+    }
+
+    #[inline(always)]
+    pub fn read_byte(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    #[inline(always)]
+    pub fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], DecodeError> {
+        if length > self.remaining() {
+            return Err(self.error(DecodeErrorKind::UnexpectedEof { needed: length, remaining: self.remaining() }));
+        }
+        let bytes = &self.bytes[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    #[inline(always)]
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        self.read_bytes(N).map(|bytes| bytes.try_into().unwrap())
+    }
+
+    #[inline(always)]
+    pub fn read<T>(&mut self) -> Result<T, DecodeError> where T: IOAble {
         T::read_from(self)
     }
+
+    /// Reads an unsigned LEB128 number that has to fit into `bits` bits.
+    /// Only the shortest encoding is accepted, so every number has one.
+    pub fn read_varint(&mut self, bits: u32) -> Result<u64, DecodeError> {
+        let start = self.position;
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_byte()?;
+            let low = (byte & 0x7f) as u64;
+            if shift >= bits || (low << shift) >> shift != low || (bits < 64 && (low << shift) >> bits != 0) {
+                return Err(DecodeError::new(start, DecodeErrorKind::Invalid("varint is too large")));
+            }
+            value |= low << shift;
+            if byte & 0x80 == 0 {
+                // a trailing zero group adds nothing but another way to write the number
+                if byte == 0 && shift > 0 {
+                    return Err(DecodeError::new(start, DecodeErrorKind::Invalid("varint is not in its shortest form")));
+                }
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
 }
 
+#[derive(Default)]
 pub struct OutputStream {
     pub bytes: Vec<u8>
 }
@@ -38,20 +225,41 @@ impl OutputStream {
         self.bytes.push(byte);
     }
 
+    #[inline(always)]
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
     #[inline(always)]
     pub fn write<T>(&mut self, stuff: T) where T: IOAble {
         stuff.write_to(self);
     }
+
+    /// Writes an unsigned LEB128 number, seven bits per byte, lowest first.
+    pub fn write_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.write_byte(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.write_byte(value as u8);
+    }
+}
+
+fn check_length(offset: usize, length: u64, max: usize) -> Result<(), DecodeError> {
+    if length > max as u64 {
+        return Err(DecodeError::new(offset, DecodeErrorKind::TooLong { length, max: max as u64 }));
+    }
+    Ok(())
 }
 
 pub trait IOAble: Sized {
     // type Output = Self;
-    fn read_from(stream: &mut InputStream) -> Option<Self>;
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError>;
     fn write_to(&self, stream: &mut OutputStream);
 }
 
 impl IOAble for i8 {
-    fn read_from(stream: &mut InputStream) -> Option<Self> {
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
         stream.read_byte().map(|b| b as i8)
     }
 
@@ -61,7 +269,7 @@ impl IOAble for i8 {
 }
 
 impl IOAble for u8 {
-    fn read_from(stream: &mut InputStream) -> Option<Self> {
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
         stream.read_byte()
     }
 
@@ -71,166 +279,88 @@ impl IOAble for u8 {
 }
 
 impl IOAble for i16 {
-    fn read_from(stream: &mut InputStream) -> Option<Self> {
-        Some(i16::from_be_bytes([
-            stream.read_byte()?,
-            stream.read_byte()?
-        ]))
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        Ok(i16::from_be_bytes(stream.read_array()?))
     }
 
     fn write_to(&self, stream: &mut OutputStream) {
-        stream.write_byte((self >> 8) as u8);
-        stream.write_byte(*self as u8);
+        stream.write_bytes(&self.to_be_bytes());
     }
 }
 
 impl IOAble for u16 {
-    fn read_from(stream: &mut InputStream) -> Option<Self> {
-        Some(u16::from_be_bytes([
-            stream.read_byte()?,
-            stream.read_byte()?
-        ]))
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        Ok(u16::from_be_bytes(stream.read_array()?))
     }
 
     fn write_to(&self, stream: &mut OutputStream) {
-        stream.write_byte((self >> 8) as u8);
-        stream.write_byte(*self as u8);
+        stream.write_bytes(&self.to_be_bytes());
     }
 }
 
 impl IOAble for i32 {
-    fn read_from(stream: &mut InputStream) -> Option<Self> {
-        Some(i32::from_be_bytes([
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?
-        ]))
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        Ok(i32::from_be_bytes(stream.read_array()?))
     }
 
     fn write_to(&self, stream: &mut OutputStream) {
-        stream.write_byte((self >> 24) as u8);
-        stream.write_byte((self >> 16) as u8);
-        stream.write_byte((self >> 8) as u8);
-        stream.write_byte(*self as u8);
+        stream.write_bytes(&self.to_be_bytes());
     }
 }
 
 impl IOAble for u32 {
-    fn read_from(stream: &mut InputStream) -> Option<Self> {
-        Some(u32::from_be_bytes([
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?
-        ]))
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        Ok(u32::from_be_bytes(stream.read_array()?))
     }
+
     fn write_to(&self, stream: &mut OutputStream) {
-        stream.write_byte((self >> 24) as u8);
-        stream.write_byte((self >> 16) as u8);
-        stream.write_byte((self >> 8) as u8);
-        stream.write_byte(*self as u8);
+        stream.write_bytes(&self.to_be_bytes());
     }
 }
 
 impl IOAble for i64 {
-    fn read_from(stream: &mut InputStream) -> Option<Self> {
-        Some(i64::from_be_bytes([
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?
-        ]))
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        Ok(i64::from_be_bytes(stream.read_array()?))
     }
 
     fn write_to(&self, stream: &mut OutputStream) {
-        stream.write_byte((self >> 56) as u8);
-        stream.write_byte((self >> 48) as u8);
-        stream.write_byte((self >> 40) as u8);
-        stream.write_byte((self >> 32) as u8);
-        stream.write_byte((self >> 24) as u8);
-        stream.write_byte((self >> 16) as u8);
-        stream.write_byte((self >> 8) as u8);
-        stream.write_byte(*self as u8);
+        stream.write_bytes(&self.to_be_bytes());
     }
 }
 
 impl IOAble for u64 {
-    fn read_from(stream: &mut InputStream) -> Option<Self> {
-        Some(u64::from_be_bytes([
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?
-        ]))
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        Ok(u64::from_be_bytes(stream.read_array()?))
     }
 
     fn write_to(&self, stream: &mut OutputStream) {
-        stream.write_byte((self >> 56) as u8);
-        stream.write_byte((self >> 48) as u8);
-        stream.write_byte((self >> 40) as u8);
-        stream.write_byte((self >> 32) as u8);
-        stream.write_byte((self >> 24) as u8);
-        stream.write_byte((self >> 16) as u8);
-        stream.write_byte((self >> 8) as u8);
-        stream.write_byte(*self as u8);
-
+        stream.write_bytes(&self.to_be_bytes());
     }
 }
 
 impl IOAble for f32 {
-    fn read_from(stream: &mut InputStream) -> Option<Self> {
-        Some(f32::from_be_bytes([
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?
-        ]))
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        Ok(f32::from_be_bytes(stream.read_array()?))
     }
+
     fn write_to(&self, stream: &mut OutputStream) {
-        stream.write_byte((self.to_bits() >> 24) as u8);
-        stream.write_byte((self.to_bits() >> 16) as u8);
-        stream.write_byte((self.to_bits() >> 8) as u8);
-        stream.write_byte(self.to_bits() as u8);
+        stream.write_bytes(&self.to_be_bytes());
     }
 }
 
 impl IOAble for f64 {
-    fn read_from(stream: &mut InputStream) -> Option<Self> {
-        Some(f64::from_be_bytes([
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?,
-            stream.read_byte()?
-        ]))
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        Ok(f64::from_be_bytes(stream.read_array()?))
     }
+
     fn write_to(&self, stream: &mut OutputStream) {
-        stream.write_byte((self.to_bits() >> 56) as u8);
-        stream.write_byte((self.to_bits() >> 48) as u8);
-        stream.write_byte((self.to_bits() >> 40) as u8);
-        stream.write_byte((self.to_bits() >> 32) as u8);
-        stream.write_byte((self.to_bits() >> 24) as u8);
-        stream.write_byte((self.to_bits() >> 16) as u8);
-        stream.write_byte((self.to_bits() >> 8) as u8);
-        stream.write_byte(self.to_bits() as u8);
+        stream.write_bytes(&self.to_be_bytes());
     }
 }
 
 impl IOAble for &str {
-    fn read_from(_: &mut InputStream) -> Option<Self> {
-        None
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        Err(stream.error(DecodeErrorKind::WriteOnly))
     }
 
     fn write_to(&self, stream: &mut OutputStream) {
@@ -238,33 +368,32 @@ impl IOAble for &str {
     }
 }
 
+/// Reads the bytes of a string whose length prefix started at `offset`.
+fn read_string_bytes(stream: &mut InputStream, offset: usize, length: u64) -> Result<String, DecodeError> {
+    stream.check_string_length(offset, length)?;
+    let start = stream.position();
+    let bytes = stream.read_bytes(length as usize)?;
+    std::str::from_utf8(bytes)
+        .map(str::to_string)
+        .map_err(|e| DecodeError::new(start + e.valid_up_to(), DecodeErrorKind::InvalidUtf8))
+}
+
 impl IOAble for String {
-    fn read_from(stream: &mut InputStream) -> Option<Self> {
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        let offset = stream.position();
         let length: u32 = stream.read()?;
-        let mut bytes = vec![0u8; length as usize];
-        for item in bytes.iter_mut() {
-            *item = stream.read_byte()?;
-        }
-
-        if let Ok(string) = String::from_utf8(bytes) {
-            Some(string)
-        }
-        else {
-            None
-        }
+        read_string_bytes(stream, offset, length as u64)
     }
     fn write_to(&self, stream: &mut OutputStream) {
         let bytes = self.as_bytes();
         stream.write(bytes.len() as u32);
-        for byte in bytes {
-            stream.write_byte(*byte);
-        }
+        stream.write_bytes(bytes);
     }
 }
 
 impl IOAble for bool {
-    fn read_from(stream: &mut InputStream) -> Option<Self> {
-        Some(stream.read_byte()? != 0)
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        Ok(stream.read_byte()? != 0)
     }
     fn write_to(&self, stream: &mut OutputStream) {
         stream.write_byte(if *self { 1 } else { 0 });
@@ -272,14 +401,18 @@ impl IOAble for bool {
 }
 
 impl<T> IOAble for Vec<T> where T: IOAble {
-    fn read_from(stream: &mut InputStream) -> Option<Self> {
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
         let length: i32 = stream.read()?;
-        let mut array = Vec::with_capacity(length as usize);
+        if length < 0 {
+            return Err(DecodeError::new(stream.position() - 4, DecodeErrorKind::NegativeLength(length)));
+        }
+        let length = stream.check_collection_length(stream.position() - 4, length as u64)?;
+        let mut array = Vec::with_capacity(stream.capacity_for(length, 1));
         for _ in 0..length {
             array.push(T::read_from(stream)?);
         }
 
-        Some(array)
+        Ok(array)
     }
 
     fn write_to(&self, stream: &mut OutputStream) {
@@ -291,11 +424,11 @@ impl<T> IOAble for Vec<T> where T: IOAble {
 }
 
 impl<T> IOAble for Option<T> where T: IOAble {
-    fn read_from(stream: &mut InputStream) -> Option<Self> {
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
         if stream.read()? {
-            Some(Some(T::read_from(stream)?))
+            Ok(Some(T::read_from(stream)?))
         } else {
-            Some(None)
+            Ok(None)
         }
     }
 
@@ -313,11 +446,387 @@ impl<T> IOAble for Option<T> where T: IOAble {
 }
 
 impl<T> IOAble for &T where T: IOAble {
-    fn read_from(_: &mut InputStream) -> Option<Self> {
-        None
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        Err(stream.error(DecodeErrorKind::WriteOnly))
     }
 
     fn write_to(&self, stream: &mut OutputStream) {
         (**self).write_to(stream);
     }
 }
+
+// Compact encodings for newer protocol versions. v1 keeps the fixed size
+// encodings above, these are only used where a message asks for them.
+
+/// `u32` as LEB128, 1 to 5 bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VarU32(pub u32);
+
+/// `u64` as LEB128, 1 to 10 bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VarU64(pub u64);
+
+/// `i32` as zigzag encoded LEB128, so small negative numbers stay small.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VarI32(pub i32);
+
+/// `i64` as zigzag encoded LEB128.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VarI64(pub i64);
+
+/// A string with a varint length prefix instead of a `u32`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CompactString(pub String);
+
+/// A list with a varint length prefix instead of an `i32`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CompactVec<T>(pub Vec<T>);
+
+impl IOAble for VarU32 {
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        stream.read_varint(32).map(|v| VarU32(v as u32))
+    }
+
+    fn write_to(&self, stream: &mut OutputStream) {
+        stream.write_varint(self.0 as u64);
+    }
+}
+
+impl IOAble for VarU64 {
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        stream.read_varint(64).map(VarU64)
+    }
+
+    fn write_to(&self, stream: &mut OutputStream) {
+        stream.write_varint(self.0);
+    }
+}
+
+impl IOAble for VarI32 {
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        let v = stream.read_varint(32)? as u32;
+        Ok(VarI32((v >> 1) as i32 ^ -((v & 1) as i32)))
+    }
+
+    fn write_to(&self, stream: &mut OutputStream) {
+        stream.write_varint(((self.0 << 1) ^ (self.0 >> 31)) as u32 as u64);
+    }
+}
+
+impl IOAble for VarI64 {
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        let v = stream.read_varint(64)?;
+        Ok(VarI64((v >> 1) as i64 ^ -((v & 1) as i64)))
+    }
+
+    fn write_to(&self, stream: &mut OutputStream) {
+        stream.write_varint(((self.0 << 1) ^ (self.0 >> 63)) as u64);
+    }
+}
+
+impl IOAble for CompactString {
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        let offset = stream.position();
+        let length = stream.read_varint(32)?;
+        read_string_bytes(stream, offset, length).map(CompactString)
+    }
+
+    fn write_to(&self, stream: &mut OutputStream) {
+        stream.write_varint(self.0.len() as u64);
+        stream.write_bytes(self.0.as_bytes());
+    }
+}
+
+impl<T> IOAble for CompactVec<T> where T: IOAble {
+    fn read_from(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        let offset = stream.position();
+        let length = stream.read_varint(32)?;
+        let length = stream.check_collection_length(offset, length)?;
+        let mut array = Vec::with_capacity(stream.capacity_for(length, 1));
+        for _ in 0..length {
+            array.push(T::read_from(stream)?);
+        }
+        Ok(CompactVec(array))
+    }
+
+    fn write_to(&self, stream: &mut OutputStream) {
+        stream.write_varint(self.0.len() as u64);
+        for item in &self.0 {
+            item.write_to(stream);
+        }
+    }
+}
+
+impl From<String> for CompactString {
+    fn from(value: String) -> Self {
+        CompactString(value)
+    }
+}
+
+impl From<&str> for CompactString {
+    fn from(value: &str) -> Self {
+        CompactString(value.to_string())
+    }
+}
+
+impl<T> From<Vec<T>> for CompactVec<T> {
+    fn from(value: Vec<T>) -> Self {
+        CompactVec(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integers_are_big_endian() {
+        let mut stream = OutputStream::new();
+        stream.write(0x0102u16);
+        stream.write(-2i32);
+        stream.write(1.5f64);
+        assert_eq!(stream.bytes, [1, 2, 0xff, 0xff, 0xff, 0xfe, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0]);
+
+        let mut input = InputStream::new(&stream.bytes);
+        assert_eq!(input.read::<u16>(), Ok(0x0102));
+        assert_eq!(input.read::<i32>(), Ok(-2));
+        assert_eq!(input.read::<f64>(), Ok(1.5));
+        assert_eq!(input.remaining(), 0);
+    }
+
+    #[test]
+    fn strings_are_length_prefixed() {
+        let mut stream = OutputStream::new();
+        stream.write("jëll");
+        assert_eq!(stream.bytes, [0, 0, 0, 5, b'j', 0xc3, 0xab, b'l', b'l']);
+        assert_eq!(InputStream::new(&stream.bytes).read::<String>().as_deref(), Ok("jëll"));
+    }
+
+    #[test]
+    fn truncated_input() {
+        let mut input = InputStream::new(&[0, 0, 0, 4, b'a']);
+        assert_eq!(input.read::<String>(), Err(DecodeError::new(4, DecodeErrorKind::UnexpectedEof { needed: 4, remaining: 1 })));
+
+        let mut input = InputStream::new(&[1, 2, 3]);
+        assert!(input.read::<u32>().is_err());
+        assert_eq!(input.remaining(), 3);
+        assert_eq!(input.read::<u16>(), Ok(0x0102));
+        assert_eq!(input.remaining(), 1);
+    }
+
+    #[test]
+    fn decode_errors() {
+        let mut input = InputStream::new(&[0, 0, 0, 3, b'a', 0xff, b'b']);
+        let error = input.read::<String>().unwrap_err();
+        assert_eq!(error, DecodeError::new(5, DecodeErrorKind::InvalidUtf8));
+
+        let mut input = InputStream::new(&[0xff, 0xff, 0xff, 0xff]);
+        let error = input.read::<Vec<u8>>().context("list").unwrap_err();
+        assert_eq!(error.kind, DecodeErrorKind::NegativeLength(-1));
+        assert_eq!(error.to_string(), "negative length -1 at byte 0 while reading list");
+    }
+
+    #[derive(Debug, PartialEq, jell_machine_derive::IOAble)]
+    struct Named {
+        flag: bool,
+        items: Vec<u16>,
+        inner: Tuple,
+    }
+
+    #[derive(Debug, PartialEq, jell_machine_derive::IOAble)]
+    struct Tuple(#[io(name = "first")] i8, String);
+
+    #[derive(Debug, PartialEq, jell_machine_derive::IOAble)]
+    #[repr(u8)]
+    enum Tagged {
+        Empty = 4,
+        Struct { value: u32 } = 7,
+        Tuple(Named) = 9,
+    }
+
+    #[test]
+    fn derived_impls() {
+        let value = Tagged::Tuple(Named { flag: true, items: vec![1, 2], inner: Tuple(-1, "a".to_string()) });
+        let mut stream = OutputStream::new();
+        stream.write(&value);
+        assert_eq!(stream.bytes, [9, 1, 0, 0, 0, 2, 0, 1, 0, 2, 0xff, 0, 0, 0, 1, b'a']);
+        assert_eq!(InputStream::new(&stream.bytes).read::<Tagged>(), Ok(value));
+
+        for value in [Tagged::Empty, Tagged::Struct { value: 5 }] {
+            let mut stream = OutputStream::new();
+            stream.write(&value);
+            assert_eq!(InputStream::new(&stream.bytes).read::<Tagged>(), Ok(value));
+        }
+
+        let error = InputStream::new(&[9, 1, 0, 0, 0, 0]).read::<Tagged>().unwrap_err();
+        assert_eq!(error.to_string(), "unexpected end of message, needed 1 bytes but only 0 left at byte 6 while reading Tuple.0.inner.first");
+        assert_eq!(InputStream::new(&[5]).read::<Tagged>().unwrap_err().to_string(), "unknown tag 5 at byte 0 while reading tag");
+    }
+
+    #[test]
+    fn limits() {
+        let limits = DecodeLimits { max_string_length: 3, max_collection_length: 2, max_message_size: 8 };
+
+        let mut input = InputStream::with_limits(&[0, 0, 0, 4, b'a', b'b', b'c', b'd'], limits);
+        assert_eq!(input.read::<String>().unwrap_err().kind, DecodeErrorKind::TooLong { length: 4, max: 3 });
+
+        let mut input = InputStream::with_limits(&[0, 0, 0, 3, 1, 2, 3], limits);
+        assert_eq!(input.read::<Vec<u8>>().unwrap_err().kind, DecodeErrorKind::TooLong { length: 3, max: 2 });
+
+        // lengths beyond the input fail without allocating
+        let mut input = InputStream::new(&[0x7f, 0xff, 0xff, 0xff]);
+        assert!(input.read::<Vec<u8>>().is_err());
+        let mut input = InputStream::new(&[0xff, 0xff, 0xff, 0xff]);
+        assert!(input.read::<String>().is_err());
+
+        assert!(InputStream::with_limits(&[0; 8], limits).check_message_size().is_ok());
+        assert!(InputStream::with_limits(&[0; 9], limits).check_message_size().is_err());
+    }
+
+    #[test]
+    fn varints() {
+        let encode = |value: u64| {
+            let mut stream = OutputStream::new();
+            stream.write(VarU64(value));
+            stream.bytes
+        };
+        assert_eq!(encode(0), [0]);
+        assert_eq!(encode(127), [0x7f]);
+        assert_eq!(encode(128), [0x80, 1]);
+        assert_eq!(encode(300), [0xac, 2]);
+        assert_eq!(encode(u32::MAX as u64), [0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert_eq!(encode(u64::MAX).len(), 10);
+
+        let mut stream = OutputStream::new();
+        for value in [0, -1, 1, -2, i32::MIN] {
+            stream.write(VarI32(value));
+        }
+        assert_eq!(stream.bytes, [0, 1, 2, 3, 0xff, 0xff, 0xff, 0xff, 0x0f]);
+
+        let mut stream = OutputStream::new();
+        stream.write(CompactString::from("jëll"));
+        stream.write(CompactVec(vec![VarU32(1), VarU32(200)]));
+        assert_eq!(stream.bytes, [5, b'j', 0xc3, 0xab, b'l', b'l', 2, 1, 0xc8, 1]);
+    }
+
+    #[test]
+    fn varint_errors() {
+        // 2^32 does not fit into a VarU32
+        let error = InputStream::new(&[0x80, 0x80, 0x80, 0x80, 0x10]).read::<VarU32>().unwrap_err();
+        assert_eq!(error, DecodeError::new(0, DecodeErrorKind::Invalid("varint is too large")));
+        assert!(InputStream::new(&[0x80, 0x80, 0x80, 0x80, 0x80, 0]).read::<VarU32>().is_err());
+        assert!(InputStream::new(&[0xff; 9].iter().copied().chain([2]).collect::<Vec<_>>()).read::<VarU64>().is_err());
+        assert_eq!(InputStream::new(&[0x80, 0x80]).read::<VarU32>().unwrap_err().kind, DecodeErrorKind::UnexpectedEof { needed: 1, remaining: 0 });
+
+        // overlong forms of 0 and 1
+        for bytes in [&[0x80, 0][..], &[0x81, 0x80, 0], &[0xff, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0]] {
+            let error = InputStream::new(bytes).read::<VarU64>().unwrap_err();
+            assert_eq!(error, DecodeError::new(0, DecodeErrorKind::Invalid("varint is not in its shortest form")));
+        }
+        assert_eq!(InputStream::new(&[0x81, 0]).read::<VarU32>().unwrap_err().kind, DecodeErrorKind::Invalid("varint is not in its shortest form"));
+        assert_eq!(InputStream::new(&[0x80, 1]).read::<VarU32>().unwrap(), VarU32(128));
+
+        let limits = DecodeLimits { max_string_length: 3, max_collection_length: 2, max_message_size: 8 };
+        let mut input = InputStream::with_limits(&[4, b'a', b'b', b'c', b'd'], limits);
+        assert_eq!(input.read::<CompactString>().unwrap_err().kind, DecodeErrorKind::TooLong { length: 4, max: 3 });
+        let mut input = InputStream::with_limits(&[3, 1, 2, 3], limits);
+        assert_eq!(input.read::<CompactVec<u8>>().unwrap_err().kind, DecodeErrorKind::TooLong { length: 3, max: 2 });
+    }
+
+    mod roundtrip {
+        use std::fmt::Debug;
+
+        use proptest::prelude::*;
+
+        use super::*;
+
+        fn roundtrip<T>(value: &T) -> T where T: IOAble + Debug {
+            let mut stream = OutputStream::new();
+            value.write_to(&mut stream);
+            let mut input = InputStream::with_limits(&stream.bytes, DecodeLimits {
+                max_string_length: usize::MAX,
+                max_collection_length: usize::MAX,
+                max_message_size: usize::MAX,
+            });
+            let decoded = input.read::<T>().unwrap();
+            assert_eq!(input.remaining(), 0, "{:?} left bytes unread", value);
+            decoded
+        }
+
+        macro_rules! roundtrip_tests {
+            ($($name:ident: $ty:ty),* $(,)?) => {
+                proptest! {
+                    $(
+                        #[test]
+                        fn $name(value: $ty) {
+                            prop_assert_eq!(roundtrip(&value), value);
+                        }
+                    )*
+                }
+            };
+        }
+
+        roundtrip_tests! {
+            i8_roundtrip: i8,
+            u8_roundtrip: u8,
+            i16_roundtrip: i16,
+            u16_roundtrip: u16,
+            i32_roundtrip: i32,
+            u32_roundtrip: u32,
+            i64_roundtrip: i64,
+            u64_roundtrip: u64,
+            bool_roundtrip: bool,
+            string_roundtrip: String,
+            vec_roundtrip: Vec<u16>,
+            option_roundtrip: Option<String>,
+            vec_of_options_roundtrip: Vec<Option<i32>>,
+        }
+
+        proptest! {
+            #[test]
+            fn f32_roundtrip(value: f32) {
+                prop_assert_eq!(roundtrip(&value).to_bits(), value.to_bits());
+            }
+
+            #[test]
+            fn f64_roundtrip(value: f64) {
+                prop_assert_eq!(roundtrip(&value).to_bits(), value.to_bits());
+            }
+
+            #[test]
+            fn nested_vec_roundtrip(value in prop::collection::vec(prop::collection::vec(any::<String>(), 0..8), 0..8)) {
+                prop_assert_eq!(roundtrip(&value), value);
+            }
+
+            #[test]
+            fn varint_roundtrip(a: u32, b: u64, c: i32, d: i64) {
+                prop_assert_eq!(roundtrip(&VarU32(a)), VarU32(a));
+                prop_assert_eq!(roundtrip(&VarU64(b)), VarU64(b));
+                prop_assert_eq!(roundtrip(&VarI32(c)), VarI32(c));
+                prop_assert_eq!(roundtrip(&VarI64(d)), VarI64(d));
+            }
+
+            #[test]
+            fn compact_roundtrip(value: String, items in prop::collection::vec(any::<i64>(), 0..16)) {
+                let value = CompactString(value);
+                prop_assert_eq!(roundtrip(&value), value);
+                let items = CompactVec(items.into_iter().map(VarI64).collect());
+                prop_assert_eq!(roundtrip(&items), items);
+            }
+
+            #[test]
+            fn str_writes_like_string(value: String) {
+                let mut borrowed = OutputStream::new();
+                borrowed.write(value.as_str());
+                let mut owned = OutputStream::new();
+                owned.write(&value);
+                prop_assert_eq!(borrowed.bytes, owned.bytes);
+            }
+
+            #[test]
+            fn any_input_decodes_or_errors(bytes: Vec<u8>) {
+                let _ = InputStream::new(&bytes).read::<Vec<Option<String>>>();
+            }
+        }
+    }
+}
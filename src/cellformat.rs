@@ -1,16 +1,16 @@
 use std::collections::HashMap;
 
-use crate::binary_io::InputStream;
-
-struct Parsed {
-    width: u16,
-    height: u16,
-    celltable: HashMap<u16, String>,
-    cells: Vec<(u32, u16)>,
-    directions: Vec<(u32, u8)>,
+use crate::binary_io::{InputStream, DecodeError, DecodeErrorKind};
+
+pub struct Parsed {
+    pub width: u16,
+    pub height: u16,
+    pub celltable: HashMap<u16, String>,
+    pub cells: Vec<(u32, u16)>,
+    pub directions: Vec<(u32, u8)>,
 }
 
-fn parse(data: Vec<u8>) -> Option<Parsed> {
+pub fn parse(data: Vec<u8>) -> Result<Parsed, DecodeError> {
 
     // header:
     //               (width u16) wwwwwwww wwwwwwww
@@ -30,25 +30,27 @@ fn parse(data: Vec<u8>) -> Option<Parsed> {
 
 
 
-    let mut stream = InputStream::new(data);
+    let mut stream = InputStream::new(&data);
     let width = stream.read()?;
     let height = stream.read()?;
 
-    let celltable_length = stream.read()?;
-    let mut celltable = HashMap::with_capacity(celltable_length as usize);
-    if celltable_length > 2 << 15 { return None; }
+    let celltable_length: u16 = stream.read()?;
+    if celltable_length > 0x7fff { return Err(stream.error(DecodeErrorKind::Invalid("celltable length exceeds 15 bits"))); }
+    let mut celltable = HashMap::with_capacity(stream.capacity_for(celltable_length as usize, 4));
     for i in 0..celltable_length {
         celltable.insert(i, stream.read()?);
     }
 
+    let start = stream.position();
     let cells_length = stream.read::<u64>()?;
-    let mut cells = Vec::with_capacity(cells_length as usize);
+    let cells_length = stream.check_collection_length(start, cells_length)?;
+    let mut cells = Vec::with_capacity(stream.capacity_for(cells_length, 6));
     for _ in 0..cells_length {
         cells.push((stream.read()?, stream.read()?));
     }
 
 
-    Some(Parsed {
+    Ok(Parsed {
         width,
         height,
         celltable,
@@ -1,17 +1,39 @@
-use futures::SinkExt;
+use std::time::SystemTime;
+
+use futures::{future::BoxFuture, SinkExt};
 use tokio_tungstenite::tungstenite::Message;
 
-use crate::{server::State, log::format_chat};
+use crate::{server::{State, find_client}, log::{LogRecord, Level, Category}, audit::{self, Query, MAX_SHOWN}, playback::{self, MIN_SPEED, MAX_SPEED}};
 
 macro_rules! log {
-    [$to:ident: $format:literal] => {
-        log!($to: $format,);
+    [$to:ident, $level:ident, $category:ident($client:expr)[$emphasis:expr]: $($format:tt)*] => {
+        $to.log(LogRecord::new(Level::$level, Category::$category, Some($client.to_string()), format!($($format)*)).emphasize($emphasis))
+    };
+    [$to:ident, $level:ident, $category:ident[$emphasis:expr]: $($format:tt)*] => {
+        $to.log(LogRecord::new(Level::$level, Category::$category, None, format!($($format)*)).emphasize($emphasis))
     };
-    [$to:ident: $format:literal, $($arg:tt)*] => {
-        let _ = $to.send(format!($format, $($arg)*)).await.unwrap();
+    [$to:ident, $level:ident, $category:ident($client:expr): $($format:tt)*] => {
+        $to.log(LogRecord::new(Level::$level, Category::$category, Some($client.to_string()), format!($($format)*)))
+    };
+    [$to:ident, $level:ident, $category:ident: $($format:tt)*] => {
+        $to.log(LogRecord::new(Level::$level, Category::$category, None, format!($($format)*)))
     };
 }
 
+type Handler = for<'a> fn(&'a State, &'a [&'a str]) -> BoxFuture<'a, ()>;
+
+/// Console commands and what runs them, completion in the input bar offers their names.
+pub const COMMANDS: &[(&str, Handler)] = &[
+    ("kick", |state, args| Box::pin(kick(state, args))),
+    ("tell", |state, args| Box::pin(tell(state, args))),
+    ("stop", |state, args| Box::pin(stop(state, args))),
+    ("reload", |state, args| Box::pin(reload(state, args))),
+    ("view", |state, args| Box::pin(view(state, args))),
+    ("filter", |state, args| Box::pin(filter(state, args))),
+    ("audit", |state, args| Box::pin(audit(state, args))),
+    ("speed", |state, args| Box::pin(speed(state, args))),
+];
+
 pub struct ChatMessage {
     pub content: String,
     pub sender: String,
@@ -23,45 +45,130 @@ pub async fn handle_message(state: &State, message: ChatMessage) {
     if message.content.starts_with('/') {
         let mut parts = message.content.split_whitespace().collect::<Vec<_>>();
         let command = parts.remove(0)[1..].to_lowercase();
-        let command = command.as_str();
-        match command {
-            "kick" => {
-                let id = parts[0];
-                let sender = {
-                    let mut lock = state.clients.lock().unwrap();
-                    lock.iter_mut().find(|(_, c)| c.0 == id).map(|(_, c)| c.1.clone())
-                };
-                match sender {
-                    Some(mut c) => {
-                        c.send(Message::Close(None)).await.unwrap();
-                    }
-                    None => {
-                        let log = &state.log;
-                        log!(log: "\x1b[31m[COMMAND] No client with id \x1b[1m{}\x1b[0;31m.\x1b[0m", id);
-                    }
-                }
+        match COMMANDS.iter().find(|(name, _)| *name == command) {
+            Some((_, run)) => run(state, &parts).await,
+            None => { log!(log, Warn, Command: "Unknown command /{}.", command); }
+        }
+    }
+    else {
+        log!(log, Info, Chat(message.sender): "{}", message.content);
+    }
+}
+
+async fn kick(state: &State, args: &[&str]) {
+    let log = &state.log;
+    let [id] = args else {
+        log!(log, Warn, Command: "Usage: /kick <id or nickname>");
+        return;
+    };
+    let sender = {
+        let lock = state.clients.lock().unwrap();
+        find_client(&lock, id).map(|c| c.sender.clone())
+    };
+    match sender {
+        Some(mut c) => {
+            // the client may be gone already
+            let _ = c.send(Message::Close(None)).await;
+        }
+        None => {
+            log!(log, Warn, Command[id]: "No client with id or nickname {}.", id);
+        }
+    }
+}
+
+async fn tell(state: &State, args: &[&str]) {
+    let log = &state.log;
+    let [id, ..] = args else {
+        log!(log, Warn, Command: "Usage: /tell <id or nickname> <message>");
+        return;
+    };
+    let msg = args[1..].join(" ");
+    let sender = {
+        let lock = state.clients.lock().unwrap();
+        find_client(&lock, id).map(|c| c.sender.clone())
+    };
+    match sender {
+        Some(mut c) => {
+            // c.send(Message::Text(msg)).await.unwrap();
+        }
+        None => {
+            log!(log, Warn, Command[id]: "No client with id or nickname {}.", id);
+        }
+    }
+}
+
+async fn stop(state: &State, _: &[&str]) {
+    state.shutdown.trigger();
+}
+
+async fn reload(state: &State, _: &[&str]) {
+    let log = &state.log;
+    match state.config.reload() {
+        Ok((old, new)) => {
+            state.connections.set_limits(new.connection_limits());
+            log!(log, Info, Server: "Reloaded configuration.");
+            let restart = new.restart_required(&old);
+            if !restart.is_empty() {
+                log!(log, Warn, Server: "Restart the server to apply changes to: {}.", restart.join(", "));
             }
-            "tell" => {
-                let id = parts[0];
-                let msg = parts[1..].join(" ");
-                let sender = {
-                    let mut lock = state.clients.lock().unwrap();
-                    lock.iter_mut().find(|(_, c)| c.0 == id).map(|(_, c)| c.1.clone())
-                };
-                match sender {
-                    Some(mut c) => {
-                        // c.send(Message::Text(msg)).await.unwrap();
+        }
+        Err(e) => {
+            log!(log, Error, Server: "Error reloading configuration: {}", e);
+        }
+    }
+}
+
+async fn view(state: &State, _: &[&str]) {
+    // the console opens the view itself, anything that gets here can't be shown
+    let log = &state.log;
+    log!(log, Warn, Command: "Usage: /view [x y], the grid view needs the terminal console.");
+}
+
+async fn filter(state: &State, _: &[&str]) {
+    let log = &state.log;
+    log!(log, Warn, Command: "Filtering the log needs the terminal console.");
+}
+
+async fn audit(state: &State, args: &[&str]) {
+    let log = &state.log;
+    let Some(audit) = &state.audit else {
+        log!(log, Warn, Command: "No audit log, set log.audit in the config file to record grid changes.");
+        return;
+    };
+    match Query::parse(args, SystemTime::now()) {
+        Ok(query) => {
+            // reading the whole file would hold up connections on this thread
+            let path = audit.path().to_path_buf();
+            match tokio::task::spawn_blocking(move || audit::search(&path, &query)).await {
+                Ok(Ok((count, shown))) => {
+                    for entry in &shown {
+                        log!(log, Info, Command: "{}", entry);
                     }
-                    None => {
-                        let log = &state.log;
-                        log!(log: "\x1b[31m[COMMAND] No client with id \x1b[1m{}\x1b[0;31m.\x1b[0m", id);
+                    match count > MAX_SHOWN {
+                        true => { log!(log, Info, Command: "{} changes match, the newest {} are shown.", count, MAX_SHOWN); }
+                        false => { log!(log, Info, Command: "{} changes match.", count); }
                     }
                 }
+                Ok(Err(e)) => { log!(log, Error, Command: "Error reading audit log: {}", e); }
+                Err(_) => {}
             }
-            _ => {}
         }
+        Err(usage) => { log!(log, Warn, Command: "{}", usage); }
     }
-    else {
-        log.send(format_chat(&message.sender, &message.content)).await.unwrap();
+}
+
+async fn speed(state: &State, args: &[&str]) {
+    let log = &state.log;
+    let Some(playback) = &state.playback else {
+        log!(log, Warn, Command: "No replay is playing, start the server with --play to play one.");
+        return;
+    };
+    match args.first().map(|speed| speed.parse::<f64>()) {
+        None => { log!(log, Info, Command: "Playing at {}x speed.", playback.speed()); }
+        Some(Ok(speed)) if playback::valid_speed(speed) => {
+            playback.set_speed(speed);
+            log!(log, Info, Command: "Playing at {}x speed.", speed);
+        }
+        _ => { log!(log, Warn, Command: "Usage: /speed [factor], 0 pauses, otherwise from {} up to {}.", MIN_SPEED, MAX_SPEED); }
     }
 }
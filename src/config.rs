@@ -0,0 +1,324 @@
+use std::{fmt, fs, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use serde::Deserialize;
+
+use jell_machine_server::binary_io::DecodeLimits;
+
+use crate::{limits::ConnectionLimits, log_file::Rotation};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub grid: GridConfig,
+    pub limits: LimitsConfig,
+    pub tls: TlsConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub ip: String,
+    pub port: u16,
+    /// Clients have to send this as `?password=` in the connection url.
+    pub password: Option<String>,
+    /// Sent to every client as a text message after connecting.
+    pub motd: Option<String>,
+    /// Server ticks per second, updates to clients are batched once per tick.
+    pub tick_rate: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GridConfig {
+    pub width: u16,
+    pub height: u16,
+    pub save_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    /// Longest string in bytes a client may send.
+    pub max_string_length: usize,
+    /// Most elements a list sent by a client may have.
+    pub max_collection_length: usize,
+    /// Largest message in bytes a client may send.
+    pub max_message_size: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Keep ANSI colors in headless output.
+    pub ansi: bool,
+    /// Log lines the console keeps for scrolling back, older ones are dropped.
+    pub scrollback: usize,
+    /// File the console keeps the entered commands in between runs.
+    pub history: Option<PathBuf>,
+    /// Directory log files are written to, there are none without it.
+    pub directory: Option<PathBuf>,
+    /// When a new log file is started.
+    pub rotate: Rotation,
+    /// Largest log file in bytes with `rotate = "size"`.
+    pub max_file_size: u64,
+    /// Old log files that are kept, the oldest are deleted beyond it. All are kept without it.
+    pub max_files: Option<usize>,
+    /// File every change to the grid is appended to, for `/audit`.
+    pub audit: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            ip: "127.0.0.1".to_string(),
+            port: 3001,
+            password: None,
+            motd: None,
+            tick_rate: 20,
+        }
+    }
+}
+
+impl Default for GridConfig {
+    fn default() -> Self {
+        Self {
+            width: 100,
+            height: 100,
+            save_path: None,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        let decode = DecodeLimits::default();
+        Self {
+            max_connections: None,
+            max_connections_per_ip: None,
+            max_string_length: decode.max_string_length,
+            max_collection_length: decode.max_collection_length,
+            max_message_size: decode.max_message_size,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            ansi: false,
+            scrollback: 10000,
+            history: None,
+            directory: None,
+            rotate: Rotation::Daily,
+            max_file_size: 10_000_000,
+            max_files: None,
+            audit: None,
+        }
+    }
+}
+
+/// Settings given on the command line, these always win over the config file.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub ip: Option<String>,
+    pub port: Option<u16>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub ansi: bool,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "could not parse {}: {}", path.display(), e),
+            ConfigError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: Option<&Path>, overrides: &Overrides) -> Result<Self, ConfigError> {
+        let mut config = match path {
+            Some(path) => {
+                let text = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+                toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?
+            }
+            None => Config::default(),
+        };
+
+        if let Some(ip) = &overrides.ip { config.server.ip = ip.clone(); }
+        if let Some(port) = overrides.port { config.server.port = port; }
+        if overrides.max_connections.is_some() { config.limits.max_connections = overrides.max_connections; }
+        if overrides.max_connections_per_ip.is_some() { config.limits.max_connections_per_ip = overrides.max_connections_per_ip; }
+        if overrides.tls_cert.is_some() { config.tls.cert = overrides.tls_cert.clone(); }
+        if overrides.tls_key.is_some() { config.tls.key = overrides.tls_key.clone(); }
+        if overrides.ansi { config.log.ansi = true; }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: &str| Err(ConfigError::Invalid(msg.to_string()));
+
+        if self.grid.width == 0 || self.grid.height == 0 {
+            return invalid("grid.width and grid.height must be at least 1");
+        }
+        if self.server.tick_rate == 0 || self.server.tick_rate > 1000 {
+            return invalid("server.tick_rate must be between 1 and 1000");
+        }
+        if self.limits.max_connections == Some(0) || self.limits.max_connections_per_ip == Some(0) {
+            return invalid("connection limits must be at least 1, leave them out for no limit");
+        }
+        if self.limits.max_string_length == 0 || self.limits.max_collection_length == 0 || self.limits.max_message_size == 0 {
+            return invalid("decode limits must be at least 1");
+        }
+        if self.log.scrollback == 0 {
+            return invalid("log.scrollback must be at least 1");
+        }
+        if self.log.max_file_size == 0 {
+            return invalid("log.max_file_size must be at least 1");
+        }
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            return invalid("tls.cert and tls.key must be given together");
+        }
+        for (name, path) in [("grid.save_path", &self.grid.save_path), ("log.history", &self.log.history), ("log.audit", &self.log.audit)] {
+            if let Some(dir) = path.as_ref().and_then(|p| p.parent()) {
+                if !dir.as_os_str().is_empty() && !dir.is_dir() {
+                    return Err(ConfigError::Invalid(format!("{}: directory {} does not exist", name, dir.display())));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.server.ip, self.server.port)
+    }
+
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            max_connections: self.limits.max_connections,
+            max_per_ip: self.limits.max_connections_per_ip,
+        }
+    }
+
+    pub fn decode_limits(&self) -> DecodeLimits {
+        DecodeLimits {
+            max_string_length: self.limits.max_string_length,
+            max_collection_length: self.limits.max_collection_length,
+            max_message_size: self.limits.max_message_size,
+        }
+    }
+
+    /// Names of settings that differ from `other` but only take effect after a restart.
+    pub fn restart_required(&self, other: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.server.ip != other.server.ip || self.server.port != other.server.port { changed.push("bind address"); }
+        if self.server.tick_rate != other.server.tick_rate { changed.push("tick rate"); }
+        if self.grid.width != other.grid.width || self.grid.height != other.grid.height { changed.push("grid size"); }
+        if self.tls.cert != other.tls.cert || self.tls.key != other.tls.key { changed.push("tls"); }
+        if self.log.ansi != other.log.ansi { changed.push("log colors"); }
+        if self.log.scrollback != other.log.scrollback { changed.push("scrollback"); }
+        if self.log.history != other.log.history { changed.push("console history"); }
+        if self.log.directory != other.log.directory || self.log.rotate != other.log.rotate || self.log.max_file_size != other.log.max_file_size || self.log.max_files != other.log.max_files { changed.push("log files"); }
+        if self.log.audit != other.log.audit { changed.push("audit log"); }
+        changed
+    }
+}
+
+/// The active configuration together with where it came from, so it can be reloaded.
+#[derive(Clone)]
+pub struct SharedConfig {
+    path: Option<PathBuf>,
+    overrides: Overrides,
+    current: Arc<Mutex<Config>>,
+}
+
+impl SharedConfig {
+    pub fn load(path: Option<PathBuf>, overrides: Overrides) -> Result<Self, ConfigError> {
+        let config = Config::load(path.as_deref(), &overrides)?;
+        Ok(Self {
+            path,
+            overrides,
+            current: Arc::new(Mutex::new(config)),
+        })
+    }
+
+    pub fn get(&self) -> Config {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Reads the config file again and returns the previous and the new configuration.
+    pub fn reload(&self) -> Result<(Config, Config), ConfigError> {
+        let config = Config::load(self.path.as_deref(), &self.overrides)?;
+        let old = std::mem::replace(&mut *self.current.lock().unwrap(), config.clone());
+        Ok((old, config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, ConfigError> {
+        let config: Config = toml::from_str(text).map_err(|e| ConfigError::Parse(PathBuf::new(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        let config = parse(include_str!("../config.example.toml")).unwrap();
+        assert_eq!(config.addr(), "127.0.0.1:3001");
+        assert_eq!((config.grid.width, config.grid.height), (100, 100));
+    }
+
+    #[test]
+    fn missing_sections_use_defaults() {
+        let config = parse("[server]\nport = 4000\n").unwrap();
+        assert_eq!(config.addr(), "127.0.0.1:4000");
+        assert_eq!(config.server.tick_rate, 20);
+        assert!(config.limits.max_connections.is_none());
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert!(matches!(parse("[grid]\nwidth = 0\n"), Err(ConfigError::Invalid(_))));
+        assert!(matches!(parse("[limits]\nmax_connections = 0\n"), Err(ConfigError::Invalid(_))));
+        assert!(matches!(parse("[log]\nscrollback = 0\n"), Err(ConfigError::Invalid(_))));
+        assert!(matches!(parse("[log]\nmax_file_size = 0\n"), Err(ConfigError::Invalid(_))));
+        assert!(matches!(parse("[log]\nrotate = \"hourly\"\n"), Err(ConfigError::Parse(..))));
+        assert!(matches!(parse("[tls]\ncert = \"cert.pem\"\n"), Err(ConfigError::Invalid(_))));
+        assert!(matches!(parse("[server]\nprot = 4000\n"), Err(ConfigError::Parse(..))));
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let overrides = Overrides { port: Some(4001), max_connections: Some(5), ..Overrides::default() };
+        let config = Config::load(None, &overrides).unwrap();
+        assert_eq!(config.server.port, 4001);
+        assert_eq!(config.limits.max_connections, Some(5));
+    }
+}
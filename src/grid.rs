@@ -1,12 +1,39 @@
-use crate::binary_io::IOAble;
+use std::{fs, io, path::Path};
 
-#[derive(Debug, Clone)]
+use jell_machine_derive::IOAble;
+use serde::{Serialize, Deserialize};
+
+use crate::binary_io::{InputStream, OutputStream, DecodeError, DecodeErrorKind, DecodeLimits, Context};
+
+/// The cells row by row, there have to be exactly `width * height` of them.
+/// In JSON empty cells are `null` and others `[id, direction]`.
+#[derive(Debug, Clone, PartialEq, IOAble, Serialize, Deserialize)]
+#[io(validate = "Grid::validate")]
+#[serde(try_from = "GridFields")]
 pub struct Grid {
     pub width: u16,
     pub height: u16,
+    #[io(with = "cells_v1")]
     pub cells: Vec<Option<(String, u8)>>,
 }
 
+#[derive(Deserialize)]
+struct GridFields {
+    width: u16,
+    height: u16,
+    cells: Vec<Option<(String, u8)>>,
+}
+
+impl TryFrom<GridFields> for Grid {
+    type Error = DecodeErrorKind;
+
+    fn try_from(fields: GridFields) -> Result<Self, Self::Error> {
+        let grid = Grid { width: fields.width, height: fields.height, cells: fields.cells };
+        grid.validate()?;
+        Ok(grid)
+    }
+}
+
 // static mut EMPTY: Option<(String, u8)> = None;
 
 impl Grid {
@@ -22,33 +49,76 @@ impl Grid {
         // if x >= self.width || y >= self.height {
         //     return &mut EMPTY;
         // }
-        &mut self.cells[(y * self.width + x) as usize]
+        &mut self.cells[y as usize * self.width as usize + x as usize]
     }
-}
 
-impl IOAble for Grid {
-    fn read_from(stream: &mut crate::binary_io::InputStream) -> Option<Self> {
-        Some(Grid {
-            width: stream.read()?,
-            height: stream.read()?,
-            cells: {
-                let len = stream.read::<u32>()?;
-                let mut cells = Vec::with_capacity(len as usize);
-                for _ in 0..len {
-                    let id = stream.read::<String>()?;
-                    let direction = stream.read::<u8>()?;
-                    cells.push(if id.is_empty() { None } else { Some((id, direction)) });
-                }
-                cells
-            }
-        })
+    fn validate(&self) -> Result<(), DecodeErrorKind> {
+        if self.cells.len() != self.width as usize * self.height as usize {
+            return Err(DecodeErrorKind::Invalid("cell count does not match the grid size"));
+        }
+        Ok(())
     }
 
-    fn write_to(&self, stream: &mut crate::binary_io::OutputStream) {
+    /// Writes the grid the way protocol v2 sends it, with runs of identical cells stored once.
+    pub fn write_v2(&self, stream: &mut OutputStream) {
         stream.write(self.width);
         stream.write(self.height);
-        stream.write(self.cells.len() as u32);
-        for cell in &self.cells {
+        cells_v2::write_to(&self.cells, stream);
+    }
+
+    pub fn read_v2(stream: &mut InputStream) -> Result<Self, DecodeError> {
+        let width = stream.read::<u16>().context("width")?;
+        let height = stream.read::<u16>().context("height")?;
+        let cells = cells_v2::read_from(stream, width as usize * height as usize).context("cells")?;
+        Ok(Grid { width, height, cells })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        // the save file may hold a larger grid than clients are allowed to send
+        let limits = DecodeLimits {
+            max_collection_length: usize::MAX,
+            max_message_size: usize::MAX,
+            ..DecodeLimits::default()
+        };
+        InputStream::with_limits(&bytes, limits).read::<Grid>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes to a temporary file first so a crash while saving never leaves a broken file.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut stream = OutputStream::new();
+        stream.write(self);
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, stream.bytes)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// Cells are a u32 count followed by id and direction of every cell,
+/// empty cells have an empty id.
+mod cells_v1 {
+    use crate::{binary_io::{InputStream, OutputStream, DecodeError, Context}, schema::{Schema, Describe, Definition, Field, Type}};
+
+    pub fn read_from(stream: &mut InputStream) -> Result<Vec<Option<(String, u8)>>, DecodeError> {
+        let start = stream.position();
+        let len = stream.read::<u32>().context("count")?;
+        let len = stream.check_collection_length(start, len as u64).context("count")?;
+        // every cell takes at least five bytes
+        let mut cells = Vec::with_capacity(stream.capacity_for(len, 5));
+        for _ in 0..len {
+            let id = stream.read::<String>().context("id")?;
+            let direction = stream.read::<u8>().context("direction")?;
+            cells.push(if id.is_empty() { None } else { Some((id, direction)) });
+        }
+        Ok(cells)
+    }
+
+    pub fn write_to(cells: &[Option<(String, u8)>], stream: &mut OutputStream) {
+        stream.write(cells.len() as u32);
+        for cell in cells {
             if let Some((id, direction)) = cell {
                 stream.write(id);
                 stream.write(direction);
@@ -59,4 +129,59 @@ impl IOAble for Grid {
             }
         }
     }
+
+    pub fn describe(schema: &mut Schema) -> Type {
+        if schema.start("Cell") {
+            let fields = vec![
+                Field { name: "id", ty: String::describe(schema), doc: "" },
+                Field { name: "direction", ty: u8::describe(schema), doc: "" },
+            ];
+            schema.define(Definition::Struct { name: "Cell", doc: "An empty id is an empty cell, its direction is ignored.", fields });
+        }
+        Type::List { length: Box::new(u32::describe(schema)), item: Box::new(Type::Ref { name: "Cell" }) }
+    }
+}
+
+/// Cells are runs of identical cells until the grid is full: a varint run
+/// length, the id as a compact string and, for non-empty cells, the direction.
+mod cells_v2 {
+    use crate::binary_io::{InputStream, OutputStream, DecodeError, DecodeErrorKind, Context, VarU32, CompactString};
+
+    pub fn read_from(stream: &mut InputStream, count: usize) -> Result<Vec<Option<(String, u8)>>, DecodeError> {
+        // the size is checked up front, a few runs can describe a huge grid
+        let count = stream.check_collection_length(stream.position(), count as u64)?;
+        // only as much as the input could fill right away, runs grow it
+        let mut cells = Vec::with_capacity(stream.capacity_for(count, 1));
+        // every cell of a run gets its own copy of the id, so the ids of the
+        // whole grid are held to the message size limit
+        let mut id_bytes = 0usize;
+        while cells.len() < count {
+            let start = stream.position();
+            let VarU32(run) = stream.read().context("run")?;
+            if run == 0 || run as usize > count - cells.len() {
+                return Err(DecodeError::new(start, DecodeErrorKind::Invalid("run does not fit into the grid"))).context("run");
+            }
+            let CompactString(id) = stream.read().context("id")?;
+            id_bytes = id_bytes.saturating_add((run as usize).saturating_mul(id.len()));
+            if id_bytes > stream.limits().max_message_size {
+                return Err(DecodeError::new(start, DecodeErrorKind::Invalid("cell ids are larger than the message size limit"))).context("run");
+            }
+            let cell = if id.is_empty() { None } else { Some((id, stream.read::<u8>().context("direction")?)) };
+            cells.resize(cells.len() + run as usize, cell);
+        }
+        Ok(cells)
+    }
+
+    pub fn write_to(cells: &[Option<(String, u8)>], stream: &mut OutputStream) {
+        for run in cells.chunk_by(|a, b| a == b) {
+            stream.write(VarU32(run.len() as u32));
+            match &run[0] {
+                Some((id, direction)) if !id.is_empty() => {
+                    stream.write(CompactString::from(id.as_str()));
+                    stream.write(direction);
+                },
+                _ => stream.write(CompactString::default()),
+            }
+        }
+    }
 }
@@ -0,0 +1,226 @@
+use jell_machine_server::grid::Grid;
+use unicode_width::UnicodeWidthChar;
+
+/// Cells per character for every zoom level after the first, which draws
+/// each cell with two characters: the start of its id and its direction.
+const SCALES: [usize; 5] = [1, 1, 2, 4, 8];
+
+/// Colors cells get by their id, so the same kind of cell always looks the same.
+const COLORS: [u8; 12] = [31, 32, 33, 34, 35, 36, 91, 92, 93, 94, 95, 96];
+
+/// A window onto the grid with a cursor on the cell being inspected.
+pub struct GridView {
+    /// The inspected cell.
+    pub x: u16,
+    pub y: u16,
+    /// The top left cell that is shown.
+    left: usize,
+    top: usize,
+    zoom: usize,
+}
+
+impl GridView {
+    pub fn new(x: u16, y: u16) -> Self {
+        Self { x, y, left: 0, top: 0, zoom: 0 }
+    }
+
+    /// Moves the cursor by `dx` and `dy` characters, staying inside the grid.
+    /// It stays at 0 0 if the grid is empty.
+    pub fn move_cursor(&mut self, dx: i32, dy: i32, grid: &Grid) {
+        let scale = self.scale() as i32;
+        self.x = (self.x as i32 + dx * scale).clamp(0, (grid.width as i32 - 1).max(0)) as u16;
+        self.y = (self.y as i32 + dy * scale).clamp(0, (grid.height as i32 - 1).max(0)) as u16;
+    }
+
+    pub fn zoom_in(&mut self) {
+        self.zoom = self.zoom.saturating_sub(1);
+    }
+
+    pub fn zoom_out(&mut self) {
+        self.zoom = (self.zoom + 1).min(SCALES.len() - 1);
+    }
+
+    /// Puts the cursor on the cell drawn at `column` and `row` of the last render.
+    pub fn click(&mut self, column: usize, row: usize, grid: &Grid) {
+        let x = self.left + column / self.columns_per_cell() * self.scale();
+        let y = self.top + row * self.scale();
+        if x < grid.width as usize && y < grid.height as usize {
+            (self.x, self.y) = (x as u16, y as u16);
+        }
+    }
+
+    /// Draws `rows` lines of `width` columns, scrolled so the cursor is visible.
+    pub fn render(&mut self, grid: &Grid, width: usize, rows: usize) -> Vec<String> {
+        let scale = self.scale();
+        let columns = width / self.columns_per_cell();
+        self.left = scroll_to(self.left, self.x as usize / scale * scale, columns * scale, grid.width as usize);
+        self.top = scroll_to(self.top, self.y as usize / scale * scale, rows * scale, grid.height as usize);
+
+        (0..rows).map(|row| {
+            let mut line = String::new();
+            for column in 0..columns {
+                let (x, y) = (self.left + column * scale, self.top + row * scale);
+                if x >= grid.width as usize || y >= grid.height as usize {
+                    line.push_str(&" ".repeat(self.columns_per_cell()));
+                    continue;
+                }
+                let selected = (x..x + scale).contains(&(self.x as usize)) && (y..y + scale).contains(&(self.y as usize));
+                if selected {
+                    line.push_str("\x1b[7m");
+                }
+                line.push_str(&self.glyph(grid, x, y));
+                if selected {
+                    line.push_str("\x1b[27m");
+                }
+            }
+            line
+        }).collect()
+    }
+
+    /// Describes the inspected cell and the zoom level.
+    pub fn status(&self, grid: &Grid) -> String {
+        let cell = match cell(grid, self.x as usize, self.y as usize) {
+            Some((id, direction)) => format!("{} facing {}", id, direction_name(*direction)),
+            None => "empty".to_string(),
+        };
+        let zoom = match (self.zoom, self.scale()) {
+            (0, _) => "2 columns per cell".to_string(),
+            (_, 1) => "1 cell per column".to_string(),
+            (_, scale) => format!("{0}x{0} cells per column", scale),
+        };
+        format!("{} {}: {}, {}", self.x, self.y, cell, zoom)
+    }
+
+    fn scale(&self) -> usize {
+        SCALES[self.zoom]
+    }
+
+    fn columns_per_cell(&self) -> usize {
+        if self.zoom == 0 { 2 } else { 1 }
+    }
+
+    /// What a character of the map shows: the cell itself when there is one
+    /// cell per character, how full the block is when zoomed out.
+    fn glyph(&self, grid: &Grid, x: usize, y: usize) -> String {
+        let scale = self.scale();
+        let block: Vec<_> = (y..(y + scale).min(grid.height as usize))
+            .flat_map(|y| (x..(x + scale).min(grid.width as usize)).map(move |x| (x, y)))
+            .filter_map(|(x, y)| cell(grid, x, y))
+            .collect();
+        let Some((id, direction)) = block.first() else {
+            return format!("\x1b[2m{:1$}\x1b[22m", "·", self.columns_per_cell());
+        };
+
+        let text = match (self.zoom, scale) {
+            (0, _) => {
+                let initial = id.chars().next().filter(|ch| ch.width() == Some(1)).unwrap_or('?');
+                format!("{}{}", initial, arrow(*direction))
+            }
+            (_, 1) => arrow(*direction).to_string(),
+            _ => {
+                let filled = (block.len() * 4 - 1) / (scale * scale);
+                ['░', '▒', '▓', '█'][filled].to_string()
+            }
+        };
+        format!("\x1b[{}m{}\x1b[39m", color(id), text)
+    }
+}
+
+fn cell(grid: &Grid, x: usize, y: usize) -> Option<&(String, u8)> {
+    grid.cells.get(y * grid.width as usize + x)?.as_ref()
+}
+
+/// Moves `start` as little as possible so `target` is inside `length` cells, without showing more than needed past the end.
+fn scroll_to(start: usize, target: usize, length: usize, end: usize) -> usize {
+    let start = if target < start {
+        target
+    }
+    else if target >= start + length {
+        target + 1 - length
+    }
+    else {
+        start
+    };
+    start.min(end.saturating_sub(length))
+}
+
+fn color(id: &str) -> u8 {
+    // FNV-1a, stable between runs
+    let hash = id.bytes().fold(0x811c9dc5u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193));
+    COLORS[hash as usize % COLORS.len()]
+}
+
+fn arrow(direction: u8) -> char {
+    ['→', '↓', '←', '↑'][direction as usize % 4]
+}
+
+pub fn direction_name(direction: u8) -> &'static str {
+    ["right", "down", "left", "up"][direction as usize % 4]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::strip_ansi;
+
+    fn grid() -> Grid {
+        let mut grid = Grid::new(6, 4);
+        *grid.get(1, 0) = Some(("mover".to_string(), 0));
+        *grid.get(2, 1) = Some(("push".to_string(), 3));
+        *grid.get(3, 1) = Some(("push".to_string(), 1));
+        grid
+    }
+
+    fn render(view: &mut GridView, grid: &Grid, width: usize, rows: usize) -> Vec<String> {
+        view.render(grid, width, rows).iter().map(|line| strip_ansi(line)).collect()
+    }
+
+    #[test]
+    fn zoom_levels() {
+        let grid = grid();
+        let mut view = GridView::new(1, 0);
+        assert_eq!(render(&mut view, &grid, 12, 2), ["· m→· · · · ", "· · p↑p↓· · "]);
+        assert!(view.render(&grid, 12, 1)[0].contains("\x1b[7m"));
+        assert_eq!(view.status(&grid), "1 0: mover facing right, 2 columns per cell");
+
+        view.zoom_out();
+        assert_eq!(render(&mut view, &grid, 8, 2), ["·→····  ", "··↑↓··  "]);
+        view.zoom_out();
+        assert_eq!(render(&mut view, &grid, 8, 2), ["░▒·     ", "···     "]);
+
+        view.zoom_in();
+        view.zoom_in();
+        view.zoom_in();
+        assert_eq!(view.zoom, 0);
+    }
+
+    #[test]
+    fn cursor_stays_visible() {
+        let grid = Grid::new(100, 100);
+        let mut view = GridView::new(0, 0);
+        view.move_cursor(50, 90, &grid);
+        view.render(&grid, 20, 10);
+        assert_eq!((view.left, view.top), (41, 81));
+        view.move_cursor(1000, 1000, &grid);
+        assert_eq!((view.x, view.y), (99, 99));
+        view.render(&grid, 20, 10);
+        assert_eq!((view.left, view.top), (90, 90));
+
+        view.click(4, 2, &grid);
+        assert_eq!((view.x, view.y), (92, 92));
+        view.move_cursor(-1000, 0, &grid);
+        view.render(&grid, 20, 10);
+        assert_eq!((view.x, view.left), (0, 0));
+    }
+
+    #[test]
+    fn empty_grid() {
+        let grid = Grid::new(0, 0);
+        let mut view = GridView::new(0, 0);
+        view.move_cursor(3, -2, &grid);
+        view.click(1, 1, &grid);
+        assert_eq!((view.x, view.y), (0, 0));
+        assert_eq!(render(&mut view, &grid, 4, 1), ["    "]);
+        assert_eq!(view.status(&grid), "0 0: empty, 2 columns per cell");
+    }
+}
@@ -0,0 +1,49 @@
+use std::{io::{self, stdin, stdout, BufRead, Write}, thread::{self, JoinHandle}};
+
+use async_channel::{Sender, Receiver};
+
+use crate::{log::{LogRecord, LOG_CAPACITY}, chat::ChatMessage, writer::{self, Sink}};
+
+/// Replacement for the terminal ui when there is no terminal: logs are printed
+/// as plain lines and commands are read line by line from stdin.
+/// The printing thread ends once the returned sender is closed and everything is printed.
+pub fn create_headless(ansi: bool) -> (Sender<LogRecord>, Receiver<ChatMessage>, JoinHandle<()>) {
+    let (ls, lr) = async_channel::bounded::<LogRecord>(LOG_CAPACITY);
+    let (cs, cr) = async_channel::unbounded();
+
+    // printing, on a thread of its own because writing to a pipe that is not
+    // read blocks
+    let printer = writer::spawn(lr, Printer { ansi }, |_| {});
+
+    // input handling, on a thread of its own because reading stdin blocks
+    // and the runtime would wait for the next line before shutting down, the
+    // channel is unbounded so sending never waits
+    thread::spawn(move || {
+        for line in stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            let line = line.trim();
+            if line.is_empty() { continue; }
+            if cs.try_send(ChatMessage { content: line.to_string(), sender: "server".into() }).is_err() {
+                break;
+            }
+        }
+    });
+
+    (ls, cr, printer)
+}
+
+/// Prints records as lines, colored only with `--ansi`.
+struct Printer {
+    ansi: bool,
+}
+
+impl Sink<LogRecord> for Printer {
+    fn write(&mut self, record: LogRecord) -> io::Result<()> {
+        let line = if self.ansi { record.line() } else { record.plain_line() };
+        writeln!(stdout().lock(), "{}", line)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        stdout().flush()
+    }
+}
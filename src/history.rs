@@ -0,0 +1,132 @@
+use std::{fs::{self, OpenOptions}, io::{self, Write}, path::PathBuf};
+
+/// Most entries kept, older ones are dropped when the file is loaded.
+const MAX_ENTRIES: usize = 1000;
+
+/// Lines entered in the console, oldest first. With a path every entry is
+/// appended to that file, so the history survives restarts.
+#[derive(Default)]
+pub struct History {
+    entries: Vec<String>,
+    path: Option<PathBuf>,
+    /// The entry shown while browsing with Up/Down and the input from before browsing.
+    browsing: Option<(usize, String)>,
+}
+
+impl History {
+    pub fn load(path: Option<PathBuf>) -> io::Result<Self> {
+        let mut entries = Vec::new();
+        if let Some(path) = &path {
+            match fs::read_to_string(path) {
+                Ok(text) => entries = text.lines().filter(|line| !line.is_empty()).map(String::from).collect(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            if entries.len() > MAX_ENTRIES {
+                entries.drain(..entries.len() - MAX_ENTRIES);
+                fs::write(path, entries.join("\n") + "\n")?;
+            }
+        }
+
+        Ok(Self { entries, path, browsing: None })
+    }
+
+    /// Adds an entered line unless it is empty or the same as the last one.
+    pub fn add(&mut self, line: &str) -> io::Result<()> {
+        self.browsing = None;
+        let line = line.trim_end();
+        if line.is_empty() || self.entries.last().map(String::as_str) == Some(line) {
+            return Ok(());
+        }
+        self.entries.push(line.to_string());
+
+        if let Some(path) = &self.path {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, index: usize) -> &str {
+        &self.entries[index]
+    }
+
+    /// Goes one entry back, `input` is kept to come back to with `newer`.
+    pub fn older(&mut self, input: &str) -> Option<&str> {
+        let index = match &self.browsing {
+            Some((0, _)) => return None,
+            Some((index, _)) => index - 1,
+            None => self.entries.len().checked_sub(1)?,
+        };
+        let draft = self.browsing.take().map_or_else(|| input.to_string(), |(_, draft)| draft);
+        self.browsing = Some((index, draft));
+        Some(&self.entries[index])
+    }
+
+    /// Goes one entry forward, past the newest one is the input from before browsing.
+    pub fn newer(&mut self) -> Option<String> {
+        let (index, draft) = self.browsing.take()?;
+        if index + 1 == self.entries.len() {
+            return Some(draft);
+        }
+        self.browsing = Some((index + 1, draft));
+        Some(self.entries[index + 1].clone())
+    }
+
+    /// The newest entry before `before` containing `query`.
+    pub fn find(&self, query: &str, before: usize) -> Option<usize> {
+        (0..before.min(self.entries.len())).rev().find(|&i| !query.is_empty() && self.entries[i].contains(query))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn browsing() {
+        let mut history = History::load(None).unwrap();
+        assert_eq!(history.older("draft"), None);
+        for line in ["/kick a", "", "hello", "hello ", "/stop"] {
+            history.add(line).unwrap();
+        }
+        assert_eq!(history.len(), 3);
+
+        assert_eq!(history.older("draft"), Some("/stop"));
+        assert_eq!(history.older("ignored"), Some("hello"));
+        assert_eq!(history.older("ignored"), Some("/kick a"));
+        assert_eq!(history.older("ignored"), None);
+        assert_eq!(history.newer().as_deref(), Some("hello"));
+        assert_eq!(history.newer().as_deref(), Some("/stop"));
+        assert_eq!(history.newer().as_deref(), Some("draft"));
+        assert_eq!(history.newer(), None);
+
+        assert_eq!(history.find("k", 3), Some(0));
+        assert_eq!(history.find("l", 3), Some(1));
+        assert_eq!(history.find("l", 1), None);
+        assert_eq!(history.find("", 3), None);
+    }
+
+    #[test]
+    fn persisted() {
+        let path = std::env::temp_dir().join(format!("jell-machine-history-{:x}", rand::random::<u64>()));
+        let mut history = History::load(Some(path.clone())).unwrap();
+        history.add("/reload").unwrap();
+        history.add("/kick a").unwrap();
+
+        let history = History::load(Some(path.clone())).unwrap();
+        assert_eq!((history.get(0), history.get(1)), ("/reload", "/kick a"));
+
+        let lines: Vec<_> = (0..MAX_ENTRIES + 5).map(|i| i.to_string()).collect();
+        fs::write(&path, lines.join("\n")).unwrap();
+        let history = History::load(Some(path.clone())).unwrap();
+        assert_eq!(history.len(), MAX_ENTRIES);
+        assert_eq!(history.get(0), "5");
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), MAX_ENTRIES);
+        fs::remove_file(path).unwrap();
+    }
+}
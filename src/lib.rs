@@ -0,0 +1,11 @@
+//! The binary protocol of the Jell Machine server, shared by the server
+//! binary and the fuzz targets in `fuzz/`.
+#![cfg_attr(test, feature(test))]
+
+pub mod binary_io;
+pub mod messages;
+pub mod cellformat;
+pub mod grid;
+pub mod protocol;
+pub mod schema;
+pub mod replay;
@@ -0,0 +1,144 @@
+use std::{collections::HashMap, net::IpAddr, sync::{Arc, Mutex}, fmt};
+
+use tokio_tungstenite::tungstenite::http::StatusCode;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionLimits {
+    pub max_connections: Option<usize>,
+    pub max_per_ip: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Rejection {
+    ServerFull,
+    TooManyFromIp,
+}
+
+impl Rejection {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Rejection::ServerFull => StatusCode::SERVICE_UNAVAILABLE,
+            Rejection::TooManyFromIp => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::ServerFull => write!(f, "server is full"),
+            Rejection::TooManyFromIp => write!(f, "too many connections from this address"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    limits: ConnectionLimits,
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Keeps track of open connections and hands out slots while the limits allow it.
+#[derive(Clone)]
+pub struct ConnectionTracker {
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl ConnectionTracker {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            counts: Arc::new(Mutex::new(Counts { limits, ..Counts::default() })),
+        }
+    }
+
+    /// Changes the limits for new connections, open connections are never closed.
+    pub fn set_limits(&self, limits: ConnectionLimits) {
+        self.counts.lock().unwrap().limits = limits;
+    }
+
+    pub fn active(&self) -> usize {
+        self.counts.lock().unwrap().total
+    }
+
+    pub fn acquire(&self, ip: IpAddr) -> Result<ConnectionSlot, Rejection> {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(max) = counts.limits.max_connections {
+            if counts.total >= max {
+                return Err(Rejection::ServerFull);
+            }
+        }
+        let from_ip = counts.per_ip.get(&ip).copied().unwrap_or(0);
+        if let Some(max) = counts.limits.max_per_ip {
+            if from_ip >= max {
+                return Err(Rejection::TooManyFromIp);
+            }
+        }
+
+        counts.total += 1;
+        counts.per_ip.insert(ip, from_ip + 1);
+        Ok(ConnectionSlot { counts: self.counts.clone(), ip })
+    }
+}
+
+/// Releases its connection slot when dropped.
+pub struct ConnectionSlot {
+    counts: Arc<Mutex<Counts>>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(count) = counts.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([127, 0, 0, last])
+    }
+
+    #[test]
+    fn global_limit() {
+        let tracker = ConnectionTracker::new(ConnectionLimits { max_connections: Some(2), max_per_ip: None });
+        let _a = tracker.acquire(ip(1)).unwrap();
+        let _b = tracker.acquire(ip(2)).unwrap();
+        assert!(matches!(tracker.acquire(ip(3)), Err(Rejection::ServerFull)));
+        assert_eq!(tracker.active(), 2);
+    }
+
+    #[test]
+    fn per_ip_limit() {
+        let tracker = ConnectionTracker::new(ConnectionLimits { max_connections: None, max_per_ip: Some(1) });
+        let _a = tracker.acquire(ip(1)).unwrap();
+        assert!(matches!(tracker.acquire(ip(1)), Err(Rejection::TooManyFromIp)));
+        let _b = tracker.acquire(ip(2)).unwrap();
+        assert_eq!(tracker.active(), 2);
+    }
+
+    #[test]
+    fn slots_are_freed_on_drop() {
+        let tracker = ConnectionTracker::new(ConnectionLimits { max_connections: Some(1), max_per_ip: Some(1) });
+        let slot = tracker.acquire(ip(1)).unwrap();
+        assert!(tracker.acquire(ip(2)).is_err());
+        drop(slot);
+        assert_eq!(tracker.active(), 0);
+        assert!(tracker.counts.lock().unwrap().per_ip.is_empty());
+        let _slot = tracker.acquire(ip(1)).unwrap();
+
+        // lowering the limits keeps open connections
+        tracker.set_limits(ConnectionLimits { max_connections: Some(0), max_per_ip: None });
+        assert_eq!(tracker.active(), 1);
+        assert!(matches!(tracker.acquire(ip(2)), Err(Rejection::ServerFull)));
+    }
+}
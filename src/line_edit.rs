@@ -0,0 +1,250 @@
+use std::ops::Range;
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// The text of the input bar. Positions are byte indices on grapheme
+/// cluster boundaries, so one step of the cursor is one character as the
+/// user sees it. Widths are terminal columns.
+#[derive(Default)]
+pub struct LineEditor {
+    text: String,
+    position: usize,
+    /// The first shown byte when the text does not fit into the input bar.
+    offset: usize,
+}
+
+impl LineEditor {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Replaces the text and puts the cursor at its end.
+    pub fn set(&mut self, text: String) {
+        self.position = text.len();
+        self.offset = 0;
+        self.text = text;
+    }
+
+    pub fn take(&mut self) -> String {
+        self.position = 0;
+        self.offset = 0;
+        std::mem::take(&mut self.text)
+    }
+
+    pub fn insert(&mut self, ch: char) {
+        self.text.insert(self.position, ch);
+        // a combining character can join the next cluster
+        self.position = self.boundary_from(self.position + ch.len_utf8());
+    }
+
+    /// Replaces everything from `start` up to the cursor.
+    pub fn replace_before(&mut self, start: usize, with: &str) {
+        self.text.replace_range(start..self.position, with);
+        self.position = start + with.len();
+    }
+
+    pub fn left(&mut self) {
+        self.position = self.previous(self.position);
+    }
+
+    pub fn right(&mut self) {
+        self.position = self.next(self.position);
+    }
+
+    pub fn home(&mut self) {
+        self.position = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.position = self.text.len();
+    }
+
+    pub fn word_left(&mut self) {
+        self.position = self.word_start(is_word);
+    }
+
+    pub fn word_right(&mut self) {
+        self.position = self.word_end(is_word);
+    }
+
+    pub fn backspace(&mut self) {
+        self.delete_before(self.previous(self.position));
+    }
+
+    pub fn delete(&mut self) {
+        self.text.replace_range(self.position..self.next(self.position), "");
+    }
+
+    /// Deletes up to the previous whitespace, like Ctrl-W in a shell.
+    pub fn delete_word_before(&mut self) {
+        self.delete_before(self.word_start(|g| !g.trim().is_empty()));
+    }
+
+    pub fn delete_word_after(&mut self) {
+        self.text.replace_range(self.position..self.word_end(is_word), "");
+    }
+
+    pub fn delete_to_start(&mut self) {
+        self.delete_before(0);
+    }
+
+    /// Scrolls so the cursor fits into `width` columns. Returns the byte range
+    /// of the text that is shown and the column of the cursor.
+    pub fn view(&mut self, width: usize) -> (Range<usize>, usize) {
+        // nothing fits, not even the cursor
+        if width == 0 {
+            return (self.position..self.position, 0);
+        }
+        self.offset = self.offset.min(self.position);
+        if self.text[..self.position].width() < width {
+            self.offset = 0;
+        }
+        while self.text[self.offset..self.position].width() >= width {
+            self.offset = self.next(self.offset);
+        }
+
+        let mut end = self.offset;
+        let mut used = 0;
+        for grapheme in self.text[self.offset..].graphemes(true) {
+            used += grapheme.width();
+            if used > width {
+                break;
+            }
+            end += grapheme.len();
+        }
+        (self.offset..end, self.text[self.offset..self.position].width())
+    }
+
+    fn delete_before(&mut self, start: usize) {
+        self.text.replace_range(start..self.position, "");
+        self.position = start;
+    }
+
+    fn previous(&self, position: usize) -> usize {
+        self.text[..position].grapheme_indices(true).next_back().map_or(0, |(i, _)| i)
+    }
+
+    fn next(&self, position: usize) -> usize {
+        self.text[position..].graphemes(true).next().map_or(position, |g| position + g.len())
+    }
+
+    /// The first boundary at or after `position`.
+    fn boundary_from(&self, position: usize) -> usize {
+        self.text.grapheme_indices(true).map(|(i, _)| i).find(|&i| i >= position).unwrap_or(self.text.len())
+    }
+
+    /// Start of the word before the cursor, skipping what is not part of a word first.
+    fn word_start(&self, is_word: impl Fn(&str) -> bool) -> usize {
+        let graphemes: Vec<_> = self.text[..self.position].grapheme_indices(true).collect();
+        let mut i = graphemes.len();
+        while i > 0 && !is_word(graphemes[i - 1].1) {
+            i -= 1;
+        }
+        while i > 0 && is_word(graphemes[i - 1].1) {
+            i -= 1;
+        }
+        graphemes.get(i).map_or(self.position, |&(index, _)| index)
+    }
+
+    /// End of the word after the cursor, skipping what is not part of a word first.
+    fn word_end(&self, is_word: impl Fn(&str) -> bool) -> usize {
+        let mut graphemes = self.text[self.position..].grapheme_indices(true).peekable();
+        while graphemes.next_if(|(_, g)| !is_word(g)).is_some() {}
+        while graphemes.next_if(|(_, g)| is_word(g)).is_some() {}
+        graphemes.peek().map_or(self.text.len(), |&(index, _)| self.position + index)
+    }
+}
+
+fn is_word(grapheme: &str) -> bool {
+    grapheme.chars().any(char::is_alphanumeric)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor(text: &str) -> LineEditor {
+        let mut editor = LineEditor::default();
+        editor.set(text.to_string());
+        editor
+    }
+
+    #[test]
+    fn graphemes() {
+        let mut input = editor("grüße 👨‍👩‍👧");
+        input.backspace();
+        assert_eq!(input.text(), "grüße ");
+        input.left();
+        input.left();
+        input.left();
+        input.insert('ä');
+        input.insert('e');
+        input.insert('\u{301}');
+        assert_eq!(input.text(), "grüäe\u{301}ße ");
+        assert_eq!(input.position(), "grüäe\u{301}".len());
+        input.backspace();
+        input.delete();
+        assert_eq!(input.text(), "grüäe ");
+
+        input.home();
+        input.right();
+        input.delete();
+        assert_eq!(input.text(), "güäe ");
+        input.end();
+        input.right();
+        input.delete();
+        assert_eq!(input.position(), input.text().len());
+    }
+
+    #[test]
+    fn words() {
+        let mut input = editor("/kick  3fa2, now");
+        input.word_left();
+        assert_eq!(input.position(), 13);
+        input.word_left();
+        assert_eq!(input.position(), 7);
+        input.word_right();
+        assert_eq!(input.position(), 11);
+        input.delete_word_after();
+        assert_eq!(input.text(), "/kick  3fa2");
+
+        input.delete_word_before();
+        assert_eq!(input.text(), "/kick  ");
+        input.delete_word_before();
+        assert_eq!(input.text(), "");
+        input.delete_word_before();
+
+        let mut input = editor("über straße");
+        input.home();
+        input.word_right();
+        assert_eq!(input.position(), "über".len());
+        input.delete_to_start();
+        assert_eq!(input.text(), " straße");
+    }
+
+    #[test]
+    fn view() {
+        // wide characters take two columns
+        let mut input = editor("ab日本語cd");
+        let len = input.text().len();
+        assert_eq!(input.view(20), (0..len, 10));
+        let (range, column) = input.view(6);
+        assert_eq!(&input.text()[range], "語cd");
+        assert_eq!(column, 4);
+
+        input.home();
+        let (range, column) = input.view(6);
+        assert_eq!(&input.text()[range], "ab日本");
+        assert_eq!(column, 0);
+
+        // a window too narrow for the input bar
+        input.end();
+        assert_eq!(input.view(0), (len..len, 0));
+        assert_eq!(input.view(1), (len..len, 0));
+    }
+}
@@ -1,3 +1,207 @@
+use std::{fmt, ops::Range, sync::{Arc, atomic::{AtomicUsize, Ordering}}};
+
+use async_channel::{Sender, TrySendError};
+
+/// Records the console or log file can fall behind by before new ones are dropped.
+pub const LOG_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Level {
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Server,
+    Client,
+    Chat,
+    Command,
+}
+
+/// One line of output. The console keeps every record and only filters what it draws.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    pub level: Level,
+    pub category: Category,
+    /// The client the record is about, for chat the sender.
+    pub client: Option<String>,
+    /// Plain text, colors and emphasis are added when it is printed.
+    pub message: String,
+    /// A part of the message shown in bold, like a path or a nickname.
+    pub emphasis: Option<String>,
+}
+
+impl LogRecord {
+    pub fn new(level: Level, category: Category, client: Option<String>, message: String) -> Self {
+        Self { level, category, client, message, emphasis: None }
+    }
+
+    pub fn emphasize(mut self, part: impl fmt::Display) -> Self {
+        self.emphasis = Some(part.to_string());
+        self
+    }
+
+    /// The record as it is printed, with its tag and colors.
+    pub fn line(&self) -> String {
+        let message = match &self.emphasis {
+            Some(part) => self.message.replacen(part.as_str(), &format!("\x1b[1m{}\x1b[22m", part), 1),
+            None => self.message.clone(),
+        };
+        let tag = match &self.client {
+            Some(client) if self.is_chat() => return format_chat(client, &message),
+            _ => self.tag(),
+        };
+        match (self.level, self.category) {
+            (Level::Warn | Level::Error, _) => format!("\x1b[31m[{}] {}\x1b[0m", tag, message),
+            (_, Category::Command) => format_log("94", &tag, &message),
+            (_, Category::Server) => format!("\x1b[33m[{}] {}\x1b[0m", tag, message),
+            (_, _) => format!("\x1b[32m[{}] {}\x1b[0m", tag, message),
+        }
+    }
+
+    /// The record with its tag but without colors, for files and output that is not a terminal.
+    pub fn plain_line(&self) -> String {
+        match &self.client {
+            Some(client) if self.is_chat() => format!("[CHAT:{}] {}", client, self.message),
+            _ => format!("[{}] {}", self.tag(), self.message),
+        }
+    }
+
+    fn is_chat(&self) -> bool {
+        self.category == Category::Chat && self.level == Level::Info
+    }
+
+    fn tag(&self) -> String {
+        match &self.client {
+            Some(client) => format!("{}:{}", self.category.name(), client),
+            None => self.category.name().to_string(),
+        }
+    }
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        [Level::Info, Level::Warn, Level::Error].into_iter().find(|level| level.name().eq_ignore_ascii_case(name))
+    }
+}
+
+impl Category {
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Server => "SERVER",
+            Category::Client => "CLIENT",
+            Category::Chat => "CHAT",
+            Category::Command => "COMMAND",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        [Category::Server, Category::Client, Category::Chat, Category::Command].into_iter().find(|category| category.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Sends log records without ever waiting, so logging can't hold up a
+/// connection. When the receiving end falls behind records are dropped and
+/// counted, the count is logged as soon as there is room again. Records sent
+/// after the receiver is gone are ignored.
+#[derive(Clone)]
+pub struct Logger {
+    sender: Sender<LogRecord>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Logger {
+    pub fn new(sender: Sender<LogRecord>) -> Self {
+        Self { sender, dropped: Arc::default() }
+    }
+
+    pub fn log(&self, record: LogRecord) {
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            let warning = LogRecord::new(Level::Warn, Category::Server, None, format!("Dropped {} log records, the console could not keep up.", dropped));
+            if self.sender.try_send(warning).is_err() {
+                self.dropped.fetch_add(dropped, Ordering::Relaxed);
+            }
+        }
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(record) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Stops taking records for every clone, the queued ones are still received.
+    pub fn close(&self) {
+        self.sender.close();
+    }
+}
+
+/// Which records the console shows, set with `/filter`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    /// The least severe level shown.
+    pub level: Level,
+    /// Shown categories, all of them when empty.
+    pub categories: Vec<Category>,
+    pub client: Option<String>,
+}
+
+pub const FILTER_USAGE: &str = "Usage: /filter [clear | level <info|warn|error> | category <server|client|chat|command|all>... | client <id|all>]";
+
+impl Filter {
+    pub fn matches(&self, record: &LogRecord) -> bool {
+        record.level >= self.level
+            && (self.categories.is_empty() || self.categories.contains(&record.category))
+            && (self.client.is_none() || self.client == record.client)
+    }
+
+    pub fn is_active(&self) -> bool {
+        *self != Filter::default()
+    }
+
+    /// Changes the filter by the arguments of `/filter`, no arguments change nothing.
+    pub fn apply(&mut self, args: &[&str]) -> Result<(), &'static str> {
+        match args {
+            [] => {}
+            ["clear" | "off"] => *self = Filter::default(),
+            ["level", level] => self.level = Level::parse(level).ok_or(FILTER_USAGE)?,
+            ["category"] | ["category", "all"] => self.categories.clear(),
+            ["category", categories @ ..] => {
+                self.categories = categories.iter().map(|name| Category::parse(name)).collect::<Option<_>>().ok_or(FILTER_USAGE)?;
+            }
+            ["client"] | ["client", "all"] => self.client = None,
+            ["client", client] => self.client = Some(client.to_string()),
+            _ => return Err(FILTER_USAGE),
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.is_active() {
+            return write!(f, "everything");
+        }
+        let mut parts = vec![format!("{} and up", self.level.name())];
+        if !self.categories.is_empty() {
+            parts.push(self.categories.iter().map(|category| category.name()).collect::<Vec<_>>().join(", "));
+        }
+        if let Some(client) = &self.client {
+            parts.push(format!("client {}", client));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
 pub fn format_chat(sender: &str, msg: &str) -> String {
     format!("\x1b[94m[CHAT:\x1b[3m{}\x1b[23m]\x1b[0m {}", sender, msg)
 }
@@ -5,3 +209,160 @@ pub fn format_chat(sender: &str, msg: &str) -> String {
 pub fn format_log(style: &str, name: &str, msg: &str) -> String {
     format!("\x1b[{}m[{}]\x1b[0m {}", style, name, msg)
 }
+
+/// Removes ANSI escape sequences, for output that is not a terminal.
+pub fn strip_ansi(msg: &str) -> String {
+    visible_chars(msg).map(|(_, ch)| ch).collect()
+}
+
+/// Shows case-insensitive matches of `query` in reverse video, ignoring escape sequences.
+pub fn highlight_matches(msg: &str, query: &str) -> String {
+    if query.is_empty() {
+        return msg.to_string();
+    }
+
+    // where every byte of the visible text is in `msg`
+    let mut text = String::with_capacity(msg.len());
+    let mut offsets = Vec::with_capacity(msg.len());
+    for (index, ch) in visible_chars(msg) {
+        text.push(ch);
+        offsets.extend(std::iter::repeat_n(index, ch.len_utf8()));
+    }
+
+    let mut output = String::with_capacity(msg.len() + 16);
+    let mut copied = 0;
+    for (index, found) in text.to_ascii_lowercase().match_indices(&query.to_ascii_lowercase()) {
+        let start = offsets[index];
+        let last = offsets[index + found.len() - 1];
+        let end = last + msg[last..].chars().next().map_or(0, char::len_utf8);
+        output.push_str(&msg[copied..start]);
+        output.push_str("\x1b[7m");
+        output.push_str(&msg[start..end]);
+        output.push_str("\x1b[27m");
+        copied = end;
+    }
+    output.push_str(&msg[copied..]);
+    output
+}
+
+/// Keeps the visible text in the byte `range` of the stripped text and every escape sequence.
+pub fn cut_visible(msg: &str, range: Range<usize>) -> String {
+    let mut output = String::with_capacity(msg.len());
+    let mut copied = 0;
+    let mut visible = 0;
+    for (index, ch) in visible_chars(msg) {
+        output.push_str(&msg[copied..index]);
+        if range.contains(&visible) {
+            output.push(ch);
+        }
+        visible += ch.len_utf8();
+        copied = index + ch.len_utf8();
+    }
+    output.push_str(&msg[copied..]);
+    output
+}
+
+/// Characters that are not part of an escape sequence, with their byte index.
+fn visible_chars(msg: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    let mut chars = msg.char_indices();
+    std::iter::from_fn(move || {
+        while let Some((index, ch)) = chars.next() {
+            if ch != '\x1b' {
+                return Some((index, ch));
+            }
+            if chars.next().map(|(_, ch)| ch) == Some('[') {
+                for (_, ch) in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&ch) {
+                        break;
+                    }
+                }
+            }
+        }
+        None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlighting() {
+        let line = format_log("32", "CLIENT:1", "Sent grüße");
+        assert_eq!(strip_ansi(&line), "[CLIENT:1] Sent grüße");
+        assert_eq!(highlight_matches(&line, "client"), "\x1b[32m[\x1b[7mCLIENT\x1b[27m:1]\x1b[0m Sent grüße");
+        assert_eq!(highlight_matches(&line, "] s"), "\x1b[32m[CLIENT:1\x1b[7m]\x1b[0m S\x1b[27ment grüße");
+        assert_eq!(highlight_matches(&line, "ÜSSE"), line);
+        assert_eq!(highlight_matches(&line, "üße"), "\x1b[32m[CLIENT:1]\x1b[0m Sent gr\x1b[7müße\x1b[27m");
+        assert_eq!(highlight_matches(&line, ""), line);
+    }
+
+    #[test]
+    fn records() {
+        let record = LogRecord::new(Level::Info, Category::Client, Some("3fa2".into()), "New connection from here to here.".into()).emphasize("here");
+        assert_eq!(record.line(), "\x1b[32m[CLIENT:3fa2] New connection from \x1b[1mhere\x1b[22m to here.\x1b[0m");
+        assert_eq!(record.plain_line(), "[CLIENT:3fa2] New connection from here to here.");
+        let record = LogRecord::new(Level::Warn, Category::Command, None, "No client.".into());
+        assert_eq!(record.line(), "\x1b[31m[COMMAND] No client.\x1b[0m");
+        assert_eq!(record.plain_line(), "[COMMAND] No client.");
+        let record = LogRecord::new(Level::Info, Category::Chat, Some("server".into()), "hi".into());
+        assert_eq!(record.line(), format_chat("server", "hi"));
+        assert_eq!(record.plain_line(), strip_ansi(&record.line()));
+    }
+
+    #[test]
+    fn filtering() {
+        let info = LogRecord::new(Level::Info, Category::Client, Some("a".into()), String::new());
+        let error = LogRecord::new(Level::Error, Category::Server, None, String::new());
+        let mut filter = Filter::default();
+        assert!(filter.matches(&info) && filter.matches(&error));
+        assert_eq!(filter.to_string(), "everything");
+
+        filter.apply(&["level", "WARN"]).unwrap();
+        assert!(!filter.matches(&info) && filter.matches(&error));
+        filter.apply(&["level", "info"]).unwrap();
+        filter.apply(&["category", "client", "chat"]).unwrap();
+        assert!(filter.matches(&info) && !filter.matches(&error));
+        filter.apply(&["client", "b"]).unwrap();
+        assert!(!filter.matches(&info));
+        assert_eq!(filter.to_string(), "info and up, CLIENT, CHAT, client b");
+
+        assert_eq!(filter.apply(&["level", "loud"]), Err(FILTER_USAGE));
+        assert_eq!(filter.apply(&["category", "client", "nope"]), Err(FILTER_USAGE));
+        assert_eq!(filter.client.as_deref(), Some("b"));
+        filter.apply(&["client", "all"]).unwrap();
+        filter.apply(&["category", "all"]).unwrap();
+        assert!(!filter.is_active());
+        filter.apply(&["level", "error"]).unwrap();
+        filter.apply(&["clear"]).unwrap();
+        assert_eq!(filter, Filter::default());
+    }
+
+    #[test]
+    fn dropping() {
+        let (sender, receiver) = async_channel::bounded(2);
+        let logger = Logger::new(sender);
+        let record = |message: &str| LogRecord::new(Level::Info, Category::Server, None, message.to_string());
+        for message in ["1", "2", "3", "4", "5"] {
+            logger.log(record(message));
+        }
+        assert_eq!(receiver.try_recv().unwrap().message, "1");
+        assert_eq!(receiver.try_recv().unwrap().message, "2");
+        assert!(receiver.is_empty());
+
+        logger.log(record("6"));
+        let warning = receiver.try_recv().unwrap();
+        assert_eq!((warning.level, warning.message.as_str()), (Level::Warn, "Dropped 3 log records, the console could not keep up."));
+        assert_eq!(receiver.try_recv().unwrap().message, "6");
+
+        drop(receiver);
+        logger.log(record("7"));
+    }
+
+    #[test]
+    fn cutting() {
+        let line = "\x1b[1;36m/kick\x1b[0m \x1b[33mgrüße\x1b[0m ";
+        assert_eq!(cut_visible(line, 0..5), "\x1b[1;36m/kick\x1b[0m\x1b[33m\x1b[0m");
+        assert_eq!(cut_visible(line, 3..10), "\x1b[1;36mck\x1b[0m \x1b[33mgrü\x1b[0m");
+    }
+}
@@ -0,0 +1,285 @@
+use std::{collections::VecDeque, fs::{self, File, OpenOptions}, io::{self, BufWriter, Write}, path::{Path, PathBuf}, thread::JoinHandle, time::SystemTime};
+
+use async_channel::Sender;
+use futures::executor::block_on;
+use serde::Deserialize;
+
+use crate::{log::{LogRecord, Level, Category, LOG_CAPACITY}, writer::{self, Sink}, time::timestamp};
+
+/// When a new log file is started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    /// One file per day, `server-2024-05-01.log`.
+    #[default]
+    Daily,
+    /// `server.log` until it reaches the size limit, full files are renamed to
+    /// `server.1.log`, `server.2.log` and so on, oldest first.
+    Size,
+}
+
+/// Log records as plain lines with a timestamp, in files that are rotated
+/// as configured. Old files are deleted beyond `max_files`, without it they
+/// are all kept for auditing. Lines are buffered until `flush`. Writing and
+/// rotating block, `tee` does them on a thread of its own.
+pub struct LogFile {
+    directory: PathBuf,
+    rotation: Rotation,
+    max_size: u64,
+    max_files: Option<usize>,
+    /// The open file, its size and the day it was opened on.
+    current: Option<(BufWriter<File>, u64, String)>,
+    /// Files that were rotated away from, oldest first.
+    old: VecDeque<PathBuf>,
+    /// What the next full `server.log` is renamed to, `server.N.log`.
+    next_number: u64,
+}
+
+impl LogFile {
+    pub fn open(directory: &Path, rotation: Rotation, max_size: u64, max_files: Option<usize>) -> io::Result<Self> {
+        fs::create_dir_all(directory)?;
+        let today = timestamp(SystemTime::now())[..10].to_string();
+        let mut file = Self {
+            directory: directory.to_path_buf(),
+            rotation,
+            max_size,
+            max_files,
+            current: None,
+            old: VecDeque::new(),
+            next_number: 1,
+        };
+        file.find_old(&today)?;
+        file.prune()?;
+        file.reopen(&today)?;
+        Ok(file)
+    }
+
+    pub fn write(&mut self, record: &LogRecord, time: SystemTime) -> io::Result<()> {
+        let timestamp = timestamp(time);
+        let line = format!("{} {:5} {}\n", timestamp, record.level.name().to_uppercase(), record.plain_line());
+        let day = &timestamp[..10];
+
+        let rotate = match (&self.current, self.rotation) {
+            (None, _) => true,
+            (Some((_, _, opened)), Rotation::Daily) => opened != day,
+            (Some((_, size, _)), Rotation::Size) => *size > 0 && size + line.len() as u64 > self.max_size,
+        };
+        if rotate {
+            if let Some((mut file, _, opened)) = self.current.take() {
+                file.flush()?;
+                drop(file);
+                match self.rotation {
+                    Rotation::Daily => self.old.push_back(self.path(&opened)),
+                    Rotation::Size => self.archive()?,
+                }
+                self.prune()?;
+            }
+            self.reopen(day)?;
+        }
+
+        let (file, size, _) = self.current.as_mut().unwrap();
+        file.write_all(line.as_bytes())?;
+        *size += line.len() as u64;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some((file, _, _)) => file.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn path(&self, day: &str) -> PathBuf {
+        match self.rotation {
+            Rotation::Daily => self.directory.join(format!("server-{}.log", day)),
+            Rotation::Size => self.directory.join("server.log"),
+        }
+    }
+
+    fn reopen(&mut self, day: &str) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(self.path(day))?;
+        let size = file.metadata()?.len();
+        self.current = Some((BufWriter::new(file), size, day.to_string()));
+        Ok(())
+    }
+
+    /// Looks for the files of earlier runs once, later rotations keep track
+    /// of the files themselves.
+    fn find_old(&mut self, today: &str) -> io::Result<()> {
+        let mut old = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let name = entry?.file_name();
+            let Some(name) = name.to_str() else { continue };
+            match self.rotation {
+                Rotation::Daily => {
+                    let day = name.strip_prefix("server-").and_then(|name| name.strip_suffix(".log"));
+                    if let Some(day) = day.filter(|day| day.len() == 10 && *day != today) {
+                        old.push((0, day.to_string()));
+                    }
+                }
+                Rotation::Size => {
+                    let number = name.strip_prefix("server.").and_then(|name| name.strip_suffix(".log"));
+                    if let Some(number) = number.and_then(|number| number.parse::<u64>().ok()) {
+                        old.push((number, String::new()));
+                    }
+                }
+            }
+        }
+        old.sort();
+        self.next_number = old.last().map_or(1, |(number, _)| number + 1);
+        self.old = old.into_iter().map(|(number, day)| match self.rotation {
+            Rotation::Daily => self.path(&day),
+            Rotation::Size => self.numbered(number),
+        }).collect();
+        Ok(())
+    }
+
+    fn numbered(&self, number: u64) -> PathBuf {
+        self.directory.join(format!("server.{}.log", number))
+    }
+
+    /// Renames the full `server.log` to the next number.
+    fn archive(&mut self) -> io::Result<()> {
+        let path = self.numbered(self.next_number);
+        fs::rename(self.path(""), &path)?;
+        self.old.push_back(path);
+        self.next_number += 1;
+        Ok(())
+    }
+
+    /// Deletes the oldest files beyond `max_files`.
+    fn prune(&mut self) -> io::Result<()> {
+        let Some(max_files) = self.max_files else { return Ok(()) };
+        while self.old.len() > max_files {
+            let path = self.old.pop_front().unwrap();
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes every record to `file` before passing it on to `console`, on a
+/// thread of its own since writing and rotating block. The file is flushed
+/// whenever no more records are waiting. Errors writing are shown on the
+/// console once until writing works again.
+/// The thread ends once the returned sender is closed and everything is written.
+pub fn tee(console: Sender<LogRecord>, file: LogFile) -> (Sender<LogRecord>, JoinHandle<()>) {
+    let (ls, lr) = async_channel::bounded::<LogRecord>(LOG_CAPACITY);
+    let errors = console.clone();
+    let thread = writer::spawn(lr, Tee { file, console }, move |e| {
+        let error = LogRecord::new(Level::Error, Category::Server, None, format!("Error writing log file: {}", e));
+        let _ = block_on(errors.send(error));
+    });
+    (ls, thread)
+}
+
+struct Tee {
+    file: LogFile,
+    console: Sender<LogRecord>,
+}
+
+impl Sink<LogRecord> for Tee {
+    fn write(&mut self, record: LogRecord) -> io::Result<()> {
+        let written = self.file.write(&record, SystemTime::now());
+        // the console may be gone already
+        let _ = block_on(self.console.send(record));
+        written
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    fn directory() -> PathBuf {
+        std::env::temp_dir().join(format!("jell-machine-logs-{:x}", rand::random::<u64>()))
+    }
+
+    fn record(message: &str) -> LogRecord {
+        LogRecord::new(Level::Info, Category::Client, Some("3fa2".to_string()), message.to_string()).emphasize(message)
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn daily_rotation() {
+        let dir = directory();
+        let mut file = LogFile::open(&dir, Rotation::Daily, 0, None).unwrap();
+        file.write(&record("first"), at(1_735_689_599)).unwrap();
+        file.write(&record("second"), at(1_735_689_600)).unwrap();
+        file.flush().unwrap();
+
+        let old = fs::read_to_string(dir.join("server-2024-12-31.log")).unwrap();
+        assert_eq!(old, "2024-12-31 23:59:59 INFO  [CLIENT:3fa2] first\n");
+        let new = fs::read_to_string(dir.join("server-2025-01-01.log")).unwrap();
+        assert_eq!(new, "2025-01-01 00:00:00 INFO  [CLIENT:3fa2] second\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn size_rotation() {
+        let dir = directory();
+        // one line is 46 bytes, two fit
+        let mut file = LogFile::open(&dir, Rotation::Size, 100, None).unwrap();
+        for message in ["line1", "line2", "line3", "line4", "line5"] {
+            file.write(&record(message), at(0)).unwrap();
+        }
+        drop(file);
+        let mut file = LogFile::open(&dir, Rotation::Size, 100, None).unwrap();
+        file.write(&record("line6"), at(0)).unwrap();
+        file.flush().unwrap();
+
+        let lines = |name: &str| fs::read_to_string(dir.join(name)).unwrap().lines().count();
+        assert_eq!((lines("server.1.log"), lines("server.2.log"), lines("server.log")), (2, 2, 2));
+        assert!(fs::read_to_string(dir.join("server.log")).unwrap().ends_with("line6\n"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn old_files_are_deleted() {
+        let dir = directory();
+        let names = || {
+            let mut names: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+            names.sort();
+            names
+        };
+
+        // one line per file
+        let mut file = LogFile::open(&dir, Rotation::Size, 10, Some(2)).unwrap();
+        for message in ["line1", "line2", "line3", "line4"] {
+            file.write(&record(message), at(0)).unwrap();
+        }
+        file.flush().unwrap();
+        assert_eq!(names(), ["server.2.log", "server.3.log", "server.log"]);
+        assert!(fs::read_to_string(dir.join("server.2.log")).unwrap().ends_with("line2\n"));
+
+        // numbers go on after a restart, fewer files are kept right away
+        drop(file);
+        let mut file = LogFile::open(&dir, Rotation::Size, 10, Some(1)).unwrap();
+        assert_eq!(names(), ["server.3.log", "server.log"]);
+        file.write(&record("line5"), at(0)).unwrap();
+        assert_eq!(names(), ["server.4.log", "server.log"]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut file = LogFile::open(&dir, Rotation::Daily, 0, Some(1)).unwrap();
+        for day in 0..3 {
+            file.write(&record("line"), at(day * 86400)).unwrap();
+        }
+        drop(file);
+        // the file of today that was opened first is the oldest one
+        assert_eq!(names(), ["server-1970-01-02.log", "server-1970-01-03.log"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,62 +1,225 @@
 #![feature(async_closure)]
-use std::sync::Mutex;
+use std::{sync::Mutex, path::PathBuf, io::{stdout, IsTerminal}, process, time::Duration};
 use tokio::net::TcpListener;
 use lazy_static::lazy_static;
 use clap::Parser;
-use crate::{grid::Grid, server::{handle_connection, State}, chat::handle_message};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use jell_machine_server::{grid::Grid, replay::Replay};
+use crate::{server::{handle_connection, handle_tls_connection, close_all, run_ticks, client_list, Clients, State}, chat::handle_message, limits::ConnectionTracker, tls::Tls, config::{SharedConfig, Overrides}, log::{LogRecord, Level, Category, Logger}, log_file::LogFile, audit::Audit, playback::{Playback, Recorder, MIN_SPEED, MAX_SPEED}};
 
-mod binary_io;
-mod messages;
-mod cellformat;
-mod grid;
 mod ui;
+mod headless;
 mod log;
 mod server;
 mod chat;
+mod limits;
+mod tls;
+mod config;
+mod shutdown;
+mod outbox;
+mod log_file;
+mod writer;
+mod time;
+mod audit;
+mod playback;
+mod history;
+mod line_edit;
+mod grid_view;
 
 // type State = (
 //     /*connected clients*/ Arc<Mutex<HashMap<SocketAddr, UnboundedSender<Message>>>>,
 //     /*log sender*/ Sender<String>,
 // );
 
-const GRID_WIDTH: u16 = 100;
-const GRID_HEIGHT: u16 = 100;
 lazy_static! {
-    static ref GRID: Mutex<Grid> = Mutex::new(Grid::new(GRID_WIDTH, GRID_HEIGHT));
+    static ref GRID: Mutex<Grid> = Mutex::new(Grid::new(0, 0));
 }
 
 macro_rules! log {
-    [$to:ident: $format:literal] => {
-        log!($to: $format,);
+    [$to:ident, $level:ident, $category:ident($client:expr)[$emphasis:expr]: $($format:tt)*] => {
+        $to.log(LogRecord::new(Level::$level, Category::$category, Some($client.to_string()), format!($($format)*)).emphasize($emphasis))
     };
-    [$to:ident: $format:literal, $($arg:tt)*] => {
-        let _ = $to.send(format!($format, $($arg)*)).await.unwrap();
+    [$to:ident, $level:ident, $category:ident[$emphasis:expr]: $($format:tt)*] => {
+        $to.log(LogRecord::new(Level::$level, Category::$category, None, format!($($format)*)).emphasize($emphasis))
+    };
+    [$to:ident, $level:ident, $category:ident($client:expr): $($format:tt)*] => {
+        $to.log(LogRecord::new(Level::$level, Category::$category, Some($client.to_string()), format!($($format)*)))
+    };
+    [$to:ident, $level:ident, $category:ident: $($format:tt)*] => {
+        $to.log(LogRecord::new(Level::$level, Category::$category, None, format!($($format)*)))
     };
 }
 
 #[derive(Parser, Debug, Clone)]
 #[clap(version, about)]
 struct Args {
-    #[clap(short, long, default_value_t = 3001)]
-    port: u16,
+    /// TOML config file, command line options take precedence over it
+    #[clap(short, long)]
+    config: Option<PathBuf>,
+
+    /// Port to listen on [default: 3001]
+    #[clap(short, long)]
+    port: Option<u16>,
+
+    /// Address to listen on [default: 127.0.0.1]
+    #[clap(short, long)]
+    ip: Option<String>,
+
+    /// Maximum number of simultaneous connections
+    #[clap(long)]
+    max_connections: Option<usize>,
+
+    /// Maximum number of simultaneous connections from a single IP address
+    #[clap(long)]
+    max_connections_per_ip: Option<usize>,
+
+    /// PEM certificate chain, enables TLS (wss://) together with --tls-key
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
 
-    #[clap(short, long, default_value = "127.0.0.1")]
-    ip: String,
+    /// Run without the terminal ui, logging plain lines to stdout and reading commands from stdin
+    #[clap(long)]
+    headless: bool,
+
+    /// Keep ANSI colors in the headless log output
+    #[clap(long)]
+    ansi: bool,
+
+    /// Record the grid and every change to it into a replay file
+    #[clap(long, conflicts_with = "play")]
+    record: Option<PathBuf>,
+
+    /// Play a replay file to connecting clients, they can't edit the grid meanwhile
+    #[clap(long)]
+    play: Option<PathBuf>,
+
+    /// Playback speed for --play, 2 plays twice as fast, change it with /speed
+    #[clap(long, default_value_t = 1.0, requires = "play")]
+    speed: f64,
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    drop(GRID.lock().unwrap());
     let args = Args::parse();
+    let config = SharedConfig::load(args.config.clone(), Overrides {
+        ip: args.ip,
+        port: args.port,
+        max_connections: args.max_connections,
+        max_connections_per_ip: args.max_connections_per_ip,
+        tls_cert: args.tls_cert,
+        tls_key: args.tls_key,
+        ansi: args.ansi,
+    }).unwrap_or_else(|e| {
+        eprintln!("Error in configuration: {}", e);
+        process::exit(1);
+    });
+    let settings = config.get();
 
-    let addr = format!("{}:{}", args.ip, args.port);
+    // a replay brings its own grid
+    let loaded_grid = match &settings.grid.save_path {
+        Some(path) if path.exists() && args.play.is_none() => match Grid::load(path) {
+            Ok(grid) => Some(grid),
+            Err(e) => {
+                eprintln!("Error loading grid from {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        },
+        _ => None,
+    };
+    let replay = args.play.as_ref().map(|path| {
+        Replay::load(path).unwrap_or_else(|e| {
+            eprintln!("Error loading replay from {}: {}", path.display(), e);
+            process::exit(1);
+        })
+    });
+    if !playback::valid_speed(args.speed) {
+        eprintln!("--speed must be 0 or between {} and {}", MIN_SPEED, MAX_SPEED);
+        process::exit(1);
+    }
+    *GRID.lock().unwrap() = match (&replay, &loaded_grid) {
+        (Some(replay), _) => replay.grid.clone(),
+        (None, Some(grid)) => grid.clone(),
+        (None, None) => Grid::new(settings.grid.width, settings.grid.height),
+    };
+    let recording = args.record.as_ref().map(|path| {
+        playback::create(path, &GRID.lock().unwrap()).unwrap_or_else(|e| {
+            eprintln!("Error creating replay {}: {}", path.display(), e);
+            process::exit(1);
+        })
+    });
+
+    let addr = settings.addr();
     let listener = TcpListener::bind(&addr).await.expect("Error listening on socket");
 
+    let tls = match (settings.tls.cert.clone(), settings.tls.key.clone()) {
+        (Some(cert), Some(key)) => Some(Tls::load(cert, key).expect("Error loading TLS certificate")),
+        _ => None,
+    };
+
     // io stuff
-    let (log, messages) = ui::create_ui();
+    let clients = Clients::default();
+    let headless = args.headless || !stdout().is_terminal();
+    let log_file = settings.log.directory.as_ref().map(|directory| {
+        LogFile::open(directory, settings.log.rotate, settings.log.max_file_size, settings.log.max_files).unwrap_or_else(|e| {
+            eprintln!("Error opening log file in {}: {}", directory.display(), e);
+            process::exit(1);
+        })
+    });
+    let audit_file = settings.log.audit.as_ref().map(|path| {
+        audit::open(path).unwrap_or_else(|e| {
+            eprintln!("Error opening audit log {}: {}", path.display(), e);
+            process::exit(1);
+        })
+    });
+    let (console, messages, printer) = if headless {
+        let (console, messages, printer) = headless::create_headless(settings.log.ansi);
+        (console, messages, Some(printer))
+    }
+    else {
+        let clients = clients.clone();
+        let (console, messages) = ui::create_ui(&settings.log, move || client_list(&clients));
+        (console, messages, None)
+    };
+    let (log, tee) = match log_file {
+        Some(file) => {
+            let (log, tee) = log_file::tee(console.clone(), file);
+            (log, Some(tee))
+        }
+        None => (console.clone(), None),
+    };
+    let log = Logger::new(log);
 
-    log!(log: "\x1b[33m[SERVER] Listening on \x1b[1m{}\x1b[0;33m.\x1b[0m", addr);
-    let state = State::new(log);
+    if let (Some(grid), Some(path)) = (&loaded_grid, &settings.grid.save_path) {
+        log!(log, Info, Server[path.display()]: "Loaded {}x{} grid from {}.", grid.width, grid.height, path.display());
+        if (grid.width, grid.height) != (settings.grid.width, settings.grid.height) {
+            log!(log, Warn, Server: "The saved grid is {}x{}, not {}x{} as configured. Delete or move the save to start with the configured size.", grid.width, grid.height, settings.grid.width, settings.grid.height);
+        }
+    }
+
+    if tls.is_some() {
+        log!(log, Info, Server[addr]: "Listening on {} with TLS.", addr);
+    }
+    else {
+        log!(log, Info, Server[addr]: "Listening on {}.", addr);
+    }
+    let connections = ConnectionTracker::new(settings.connection_limits());
+    let audit = settings.log.audit.clone().zip(audit_file).map(|(path, file)| Audit::new(path, file, log.clone()));
+    let recorder = recording.map(|file| Recorder::new(file, log.clone()));
+    let playback = replay.as_ref().map(|_| Playback::new(args.speed));
+    let state = State::new(log, clients, config, connections, audit, recorder, playback);
+
+    // replay playback
+    if let (Some(replay), Some(playback)) = (replay, state.playback.clone()) {
+        let log = &state.log;
+        let path = args.play.unwrap();
+        log!(log, Info, Server[path.display()]: "Playing {} changes from {} at {}x speed.", replay.frames.len(), path.display(), args.speed);
+        tokio::spawn(playback::play(replay.frames, playback, state.clone()));
+    }
 
     // chat messages
     let state1 = state.clone();
@@ -66,8 +229,107 @@ async fn main() {
         }
     });
 
+    // batched updates
+    tokio::spawn(run_ticks(state.clone(), settings.server.tick_rate));
+
+    // certificate reloading
+    #[cfg(unix)]
+    if let Some(tls) = tls.clone() {
+        let log = state.log.clone();
+        tokio::spawn(async move {
+            let mut hangup = signal(SignalKind::hangup()).expect("Error listening for SIGHUP");
+            while hangup.recv().await.is_some() {
+                match tls.reload() {
+                    Ok(()) => { log!(log, Info, Server: "Reloaded TLS certificate."); }
+                    Err(e) => { log!(log, Error, Server: "Error reloading TLS certificate: {}", e); }
+                }
+            }
+        });
+    }
+
+    // stop signals
+    let shutdown = state.shutdown.clone();
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        {
+            let mut terminate = signal(SignalKind::terminate()).expect("Error listening for SIGTERM");
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {},
+                _ = terminate.recv() => {},
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+        shutdown.trigger();
+    });
+
     // accept connections
-    while let Ok((stream, addr)) = listener.accept().await {
-        tokio::spawn(handle_connection(stream, addr, state.clone()));
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            },
+            _ = state.shutdown.wait() => break,
+        };
+        match &tls {
+            Some(tls) => tokio::spawn(handle_tls_connection(stream, addr, tls.clone(), state.clone())),
+            None => tokio::spawn(handle_connection(stream, addr, state.clone())),
+        };
+    }
+    drop(listener);
+
+    // shutdown
+    let log = state.log.clone();
+    log!(log, Info, Server: "Shutting down.");
+    close_all(&state, "Server is shutting down");
+    let closed = tokio::time::timeout(Duration::from_secs(5), async {
+        while state.connections.active() > 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }).await;
+    if closed.is_err() {
+        log!(log, Warn, Server: "{} connections did not close in time.", state.connections.active());
+    }
+
+    if state.playback.is_some() {
+        log!(log, Info, Server: "Played a replay, the grid was not saved.");
+    }
+    else if let Some(path) = state.config.get().grid.save_path {
+        let saved = GRID.lock().unwrap().save(&path);
+        match saved {
+            Ok(()) => { log!(log, Info, Server[path.display()]: "Saved grid to {}.", path.display()); }
+            Err(e) => { log!(log, Error, Server: "Error saving grid to {}: {}", path.display(), e); }
+        }
+    }
+    else {
+        log!(log, Warn, Server: "No save path configured, the grid was not saved.");
+    }
+    if let Some(audit) = &state.audit {
+        audit.finish().await;
+    }
+    if let Some(recorder) = &state.recorder {
+        recorder.finish().await;
+    }
+
+    // the threads writing the log end once they have written everything
+    log.close();
+    if let Some(tee) = tee {
+        writer::join(tee).await;
+    }
+    match printer {
+        Some(printer) => {
+            console.close();
+            writer::join(printer).await;
+        }
+        None => {
+            // give the console a chance to draw everything
+            while !(console.is_empty() || console.is_closed()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    }
+    if !headless {
+        ui::restore_terminal();
     }
 }
@@ -1,53 +1,612 @@
-use crate::{binary_io::{OutputStream, InputStream}, grid::Grid};
+use std::fmt;
 
-#[derive(Debug, Clone)]
+use jell_machine_derive::IOAble;
+use serde::{Serialize, Deserialize};
+
+use crate::{binary_io::{OutputStream, InputStream, DecodeError, DecodeErrorKind, Context, CompactString, VarU32}, grid::Grid, protocol::{Protocol, Version, Compression}};
+
+/// Most messages a batch can hold.
+pub const MAX_BATCH_LENGTH: usize = 4096;
+
+const BATCH_CONTENT: &str = "batches can only contain SetCell and Delete";
+
+/// Why `JMMessage::batch` refused to build a batch, no decoder would accept it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidBatch(pub &'static str);
+
+impl fmt::Display for InvalidBatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidBatch {}
+
+/// In JSON messages look like `{"type": "SetCell", "data": [x, y, id, direction]}`,
+/// `GetGrid` has no `data`.
+#[derive(Debug, Clone, PartialEq, IOAble, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+#[io(tag = "message tag")]
+#[repr(u8)]
 pub enum JMMessage {
-    GetGrid,
-    SetGrid(Grid),
-    SetCell(u16, u16, String, u8),
-    Delete(u16, u16),
+    GetGrid = 0,
+    SetGrid(#[io(name = "grid")] Grid) = 1,
+    SetCell(
+        #[io(name = "x")] u16,
+        #[io(name = "y")] u16,
+        #[io(name = "id")] String,
+        #[io(name = "direction")] u8,
+    ) = 2,
+    Delete(#[io(name = "x")] u16, #[io(name = "y")] u16) = 3,
+    /// Several messages in one frame, they are handled in order. Only v2
+    /// and JSON have batches, build them with `JMMessage::batch`.
+    #[io(skip)]
+    Batch(Vec<JMMessage>) = 4,
 }
 
 impl JMMessage {
+    /// Puts `messages` into a batch if every decoder would accept it: at most
+    /// `MAX_BATCH_LENGTH` messages, all of them `SetCell` or `Delete`.
+    pub fn batch(messages: Vec<JMMessage>) -> Result<JMMessage, InvalidBatch> {
+        if messages.len() > MAX_BATCH_LENGTH {
+            return Err(InvalidBatch("batch is too long"));
+        }
+        if !messages.iter().all(|message| matches!(message, JMMessage::SetCell(..) | JMMessage::Delete(..))) {
+            return Err(InvalidBatch(BATCH_CONTENT));
+        }
+        Ok(JMMessage::Batch(messages))
+    }
+
+    /// v1 has no batches, writing one panics.
     pub fn write_v1(&self, stream: &mut OutputStream) {
+        stream.write(self);
+    }
+
+    pub fn parse_v1(stream: &mut InputStream) -> Result<JMMessage, DecodeError> {
+        stream.check_message_size()?;
+        stream.read()
+    }
+
+    pub fn write(&self, stream: &mut OutputStream, protocol: Protocol) {
+        match protocol.version {
+            Version::V1 => self.write_v1(stream),
+            Version::V2 => self.write_v2(stream, protocol.compression),
+            Version::Json => stream.write_bytes(&serde_json::to_vec(self).unwrap()),
+        }
+    }
+
+    pub fn parse(stream: &mut InputStream, protocol: Protocol) -> Result<JMMessage, DecodeError> {
+        match protocol.version {
+            Version::V1 => JMMessage::parse_v1(stream),
+            Version::V2 => JMMessage::parse_v2(stream, protocol.compression),
+            Version::Json => JMMessage::parse_json(stream),
+        }
+    }
+
+    /// Takes the rest of the input as one JSON message. It is held to the
+    /// same limits as binary messages, errors about them point at its start.
+    pub fn parse_json(stream: &mut InputStream) -> Result<JMMessage, DecodeError> {
+        stream.check_message_size()?;
+        let start = stream.position();
+        let text = stream.read_bytes(stream.remaining())?;
+        let message: JMMessage = serde_json::from_slice(text).map_err(|e| {
+            // serde reports lines and columns, errors here point at bytes
+            let line_start: usize = text.split(|&b| b == b'\n').take(e.line().saturating_sub(1)).map(|line| line.len() + 1).sum();
+            let offset = (line_start + e.column().saturating_sub(1)).min(text.len());
+            let message = e.to_string();
+            let message = message.trim_end_matches(&format!(" at line {} column {}", e.line(), e.column()));
+            DecodeError::new(start + offset, DecodeErrorKind::Json(message.to_string()))
+        })?;
+        message.check_limits(stream, start)?;
+        Ok(message)
+    }
+
+    /// What the binary decoders check while reading, for messages serde has read.
+    fn check_limits(&self, stream: &InputStream, start: usize) -> Result<(), DecodeError> {
         match self {
-            JMMessage::GetGrid => {
-                stream.write(0u8);
+            JMMessage::GetGrid | JMMessage::Delete(..) => Ok(()),
+            JMMessage::SetGrid(grid) => (|| {
+                stream.check_collection_length(start, grid.cells.len() as u64)?;
+                for (id, _) in grid.cells.iter().flatten() {
+                    stream.check_string_length(start, id.len() as u64).context("id")?;
+                }
+                Ok(())
+            })().context("cells").context("grid").context("SetGrid"),
+            JMMessage::SetCell(_, _, id, _) => {
+                stream.check_string_length(start, id.len() as u64).context("id").context("SetCell")?;
+                Ok(())
             },
+            JMMessage::Batch(messages) => (|| {
+                check_batch_length(stream, start, messages.len() as u64)?;
+                for message in messages {
+                    if !matches!(message, JMMessage::SetCell(..) | JMMessage::Delete(..)) {
+                        return Err(DecodeError::new(start, DecodeErrorKind::Invalid(BATCH_CONTENT)));
+                    }
+                    message.check_limits(stream, start)?;
+                }
+                Ok(())
+            })().context("messages").context("Batch"),
+        }
+    }
+
+    /// Same tags as v1 plus `Batch`, but strings have varint lengths and the
+    /// grid is run-length encoded and possibly compressed. As the grid takes
+    /// up the rest of the frame, batches can only hold `SetCell` and `Delete`.
+    pub fn write_v2(&self, stream: &mut OutputStream, compression: Compression) {
+        match self {
+            JMMessage::GetGrid => stream.write(0u8),
             JMMessage::SetGrid(grid) => {
                 stream.write(1u8);
-                stream.write(grid);
+                let mut payload = OutputStream::new();
+                grid.write_v2(&mut payload);
+                stream.write_bytes(&compression.compress(payload.bytes));
             },
             JMMessage::SetCell(x, y, id, direction) => {
                 stream.write(2u8);
-                stream.write(*x);
-                stream.write(*y);
-                stream.write(id);
-                stream.write(*direction);
+                stream.write(x);
+                stream.write(y);
+                stream.write(CompactString::from(id.as_str()));
+                stream.write(direction);
             },
             JMMessage::Delete(x, y) => {
                 stream.write(3u8);
-                stream.write(*x);
-                stream.write(*y);
+                stream.write(x);
+                stream.write(y);
             },
+            JMMessage::Batch(messages) => {
+                stream.write(4u8);
+                stream.write(VarU32(messages.len() as u32));
+                for message in messages {
+                    message.write_v2(stream, compression);
+                }
+            },
+        }
+    }
+
+    pub fn parse_v2(stream: &mut InputStream, compression: Compression) -> Result<JMMessage, DecodeError> {
+        stream.check_message_size()?;
+        let start = stream.position();
+        match stream.read::<u8>().context("message tag")? {
+            0 => Ok(JMMessage::GetGrid),
+            1 => (|| {
+                let grid = match compression {
+                    Compression::None => Grid::read_v2(stream)?,
+                    _ => {
+                        let offset = stream.position();
+                        let max_size = stream.limits().max_message_size;
+                        let payload = stream.read_bytes(stream.remaining())?;
+                        let data = compression.decompress(payload, max_size).map_err(|kind| DecodeError::new(offset, kind))?;
+                        Grid::read_v2(&mut InputStream::with_limits(&data, *stream.limits()))?
+                    },
+                };
+                Ok(JMMessage::SetGrid(grid))
+            })().context("grid").context("SetGrid"),
+            2 => (|| {
+                let x = stream.read().context("x")?;
+                let y = stream.read().context("y")?;
+                let CompactString(id) = stream.read().context("id")?;
+                let direction = stream.read().context("direction")?;
+                Ok(JMMessage::SetCell(x, y, id, direction))
+            })().context("SetCell"),
+            3 => (|| {
+                let x = stream.read().context("x")?;
+                let y = stream.read().context("y")?;
+                Ok(JMMessage::Delete(x, y))
+            })().context("Delete"),
+            4 => (|| {
+                let offset = stream.position();
+                let VarU32(count) = stream.read()?;
+                let count = check_batch_length(stream, offset, count as u64)?;
+                // every message takes at least five bytes
+                let mut messages = Vec::with_capacity(stream.capacity_for(count, 5));
+                for _ in 0..count {
+                    if !matches!(peek_tag(stream)?, 2 | 3) {
+                        return Err(stream.error(DecodeErrorKind::Invalid(BATCH_CONTENT)));
+                    }
+                    messages.push(JMMessage::parse_v2(stream, compression)?);
+                }
+                Ok(JMMessage::Batch(messages))
+            })().context("messages").context("Batch"),
+            tag => Err(DecodeError::new(start, DecodeErrorKind::UnknownTag(tag))).context("message tag"),
+        }
+    }
+}
+
+fn peek_tag(stream: &InputStream) -> Result<u8, DecodeError> {
+    stream.clone().read_byte()
+}
+
+fn check_batch_length(stream: &InputStream, offset: usize, count: u64) -> Result<usize, DecodeError> {
+    let count = stream.check_collection_length(offset, count)?;
+    if count > MAX_BATCH_LENGTH {
+        return Err(DecodeError::new(offset, DecodeErrorKind::TooLong { length: count as u64, max: MAX_BATCH_LENGTH as u64 }));
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{binary_io::DecodeLimits, schema::Schema};
+
+    use super::*;
+
+    fn encode(message: &JMMessage) -> Vec<u8> {
+        let mut stream = OutputStream::new();
+        message.write_v1(&mut stream);
+        stream.bytes
+    }
+
+    #[test]
+    fn wire_format_v1() {
+        let mut grid = Grid::new(2, 1);
+        grid.cells[1] = Some(("mover".to_string(), 3));
+        let messages = [
+            (JMMessage::GetGrid, vec![0]),
+            (JMMessage::SetGrid(grid), vec![
+                1, 0, 2, 0, 1, 0, 0, 0, 2,
+                0, 0, 0, 0, 0,
+                0, 0, 0, 5, b'm', b'o', b'v', b'e', b'r', 3,
+            ]),
+            (JMMessage::SetCell(258, 3, "push".to_string(), 1), vec![2, 1, 2, 0, 3, 0, 0, 0, 4, b'p', b'u', b's', b'h', 1]),
+            (JMMessage::Delete(1, 65535), vec![3, 0, 1, 255, 255]),
+        ];
+
+        for (message, bytes) in messages {
+            assert_eq!(encode(&message), bytes, "{:?}", message);
+            let decoded = JMMessage::parse_v1(&mut InputStream::new(&bytes)).unwrap();
+            assert_eq!(encode(&decoded), bytes, "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn decode_errors_name_the_field() {
+        let error = JMMessage::parse_v1(&mut InputStream::new(&[9])).unwrap_err();
+        assert_eq!(error.to_string(), "unknown tag 9 at byte 0 while reading message tag");
+
+        let error = JMMessage::parse_v1(&mut InputStream::new(&[2, 0, 1, 0, 2, 0, 0])).unwrap_err();
+        assert_eq!(error.to_string(), "unexpected end of message, needed 4 bytes but only 2 left at byte 5 while reading SetCell.id");
+    }
+
+    #[test]
+    fn grid_size_must_match_cells() {
+        let mut bytes = encode(&JMMessage::SetGrid(Grid::new(2, 2)));
+        bytes[2] = 3;
+        let error = JMMessage::parse_v1(&mut InputStream::new(&bytes)).unwrap_err();
+        assert_eq!(error.to_string(), "cell count does not match the grid size at byte 1 while reading SetGrid.grid");
+
+        // a huge cell count fails on the missing data instead of allocating
+        let error = JMMessage::parse_v1(&mut InputStream::new(&[1, 0xff, 0xff, 0xff, 0xff, 0, 0x0f, 0xff, 0xfe])).unwrap_err();
+        assert!(matches!(error.kind, DecodeErrorKind::UnexpectedEof { .. }));
+    }
+
+    const V2: Protocol = Protocol { version: Version::V2, compression: Compression::None };
+
+    #[test]
+    fn wire_format_v2() {
+        let mut grid = Grid::new(3, 2);
+        grid.cells[4] = Some(("mover".to_string(), 3));
+        grid.cells[5] = Some(("mover".to_string(), 3));
+        let messages = [
+            (JMMessage::GetGrid, vec![0]),
+            (JMMessage::SetGrid(grid), vec![1, 0, 3, 0, 2, 4, 0, 2, 5, b'm', b'o', b'v', b'e', b'r', 3]),
+            (JMMessage::SetCell(258, 3, "push".to_string(), 1), vec![2, 1, 2, 0, 3, 4, b'p', b'u', b's', b'h', 1]),
+            (JMMessage::Delete(1, 65535), vec![3, 0, 1, 255, 255]),
+            (JMMessage::Batch(vec![JMMessage::SetCell(1, 2, "a".to_string(), 0), JMMessage::Delete(1, 2)]), vec![4, 2, 2, 0, 1, 0, 2, 1, b'a', 0, 3, 0, 1, 0, 2]),
+        ];
+
+        for (message, bytes) in messages {
+            let mut stream = OutputStream::new();
+            message.write(&mut stream, V2);
+            assert_eq!(stream.bytes, bytes, "{:?}", message);
+            assert_eq!(JMMessage::parse(&mut InputStream::new(&bytes), V2).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn v2_grid_errors() {
+        // runs have to add up to exactly the grid size
+        let error = JMMessage::parse(&mut InputStream::new(&[1, 0, 2, 0, 2, 5, 0]), V2).unwrap_err();
+        assert_eq!(error.to_string(), "run does not fit into the grid at byte 5 while reading SetGrid.grid.cells.run");
+        let error = JMMessage::parse(&mut InputStream::new(&[1, 0, 2, 0, 2, 0, 0]), V2).unwrap_err();
+        assert_eq!(error.kind, DecodeErrorKind::Invalid("run does not fit into the grid"));
+
+        // one run can not describe a grid larger than the collection limit
+        let error = JMMessage::parse(&mut InputStream::new(&[1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x0f, 0]), V2).unwrap_err();
+        assert!(matches!(error.kind, DecodeErrorKind::TooLong { .. }));
+
+        // without limits a header alone doesn't reserve space for the whole grid
+        let limits = DecodeLimits { max_collection_length: usize::MAX, max_message_size: usize::MAX, ..DecodeLimits::default() };
+        let error = JMMessage::parse(&mut InputStream::with_limits(&[1, 0xff, 0xff, 0xff, 0xff], limits), V2).unwrap_err();
+        assert_eq!(error.to_string(), "unexpected end of message, needed 1 bytes but only 0 left at byte 5 while reading SetGrid.grid.cells.run");
+
+        // a long id repeated over the whole grid would take a gigabyte
+        let mut stream = OutputStream::new();
+        stream.write(1u8);
+        stream.write(1024u16);
+        stream.write(1024u16);
+        stream.write(VarU32(1 << 20));
+        stream.write(CompactString::from("a".repeat(1000).as_str()));
+        stream.write(0u8);
+        let error = JMMessage::parse(&mut InputStream::new(&stream.bytes), V2).unwrap_err();
+        assert_eq!(error.to_string(), "cell ids are larger than the message size limit at byte 5 while reading SetGrid.grid.cells.run");
+
+        let zstd = Protocol { version: Version::V2, compression: Compression::Zstd };
+        let error = JMMessage::parse(&mut InputStream::new(&[1, 1, 2, 3]), zstd).unwrap_err();
+        assert_eq!(error.to_string(), "corrupt compressed data at byte 1 while reading SetGrid.grid");
+    }
+
+    #[test]
+    fn batches_are_flat() {
+        let error = JMMessage::parse(&mut InputStream::new(&[4, 1, 1, 0, 1, 0, 1, 0, 0]), V2).unwrap_err();
+        assert_eq!(error.to_string(), "batches can only contain SetCell and Delete at byte 2 while reading Batch.messages");
+    }
+
+    #[test]
+    fn v1_has_no_batches() {
+        let error = JMMessage::parse_v1(&mut InputStream::new(&[4, 0, 0, 0, 1, 3, 0, 1, 0, 2])).unwrap_err();
+        assert_eq!(error.to_string(), "unknown tag 4 at byte 0 while reading message tag");
+    }
+
+    #[test]
+    fn batch_length_is_limited() {
+        let error = JMMessage::parse(&mut InputStream::new(&[4, 0x81, 0x20]), V2).unwrap_err();
+        assert_eq!(error.to_string(), "length 4097 exceeds the limit of 4096 at byte 1 while reading Batch.messages");
+    }
+
+    #[test]
+    fn batches_are_checked_when_built() {
+        let deletes = |count| vec![JMMessage::Delete(0, 0); count];
+        assert_eq!(JMMessage::batch(deletes(MAX_BATCH_LENGTH)), Ok(JMMessage::Batch(deletes(MAX_BATCH_LENGTH))));
+        assert_eq!(JMMessage::batch(deletes(MAX_BATCH_LENGTH + 1)), Err(InvalidBatch("batch is too long")));
+        assert_eq!(JMMessage::batch(vec![JMMessage::Batch(Vec::new())]), Err(InvalidBatch(BATCH_CONTENT)));
+        assert_eq!(JMMessage::batch(vec![JMMessage::GetGrid]), Err(InvalidBatch(BATCH_CONTENT)));
+    }
+
+    #[test]
+    fn json_format() {
+        let json = Protocol::parse("json").unwrap();
+        let mut grid = Grid::new(2, 1);
+        grid.cells[1] = Some(("mover".to_string(), 3));
+        let messages = [
+            (JMMessage::GetGrid, r#"{"type":"GetGrid"}"#),
+            (JMMessage::SetGrid(grid), r#"{"type":"SetGrid","data":{"width":2,"height":1,"cells":[null,["mover",3]]}}"#),
+            (JMMessage::SetCell(258, 3, "push".to_string(), 1), r#"{"type":"SetCell","data":[258,3,"push",1]}"#),
+            (JMMessage::Batch(vec![JMMessage::Delete(1, 2)]), r#"{"type":"Batch","data":[{"type":"Delete","data":[1,2]}]}"#),
+        ];
+
+        for (message, text) in messages {
+            let mut stream = OutputStream::new();
+            message.write(&mut stream, json);
+            assert_eq!(std::str::from_utf8(&stream.bytes).unwrap(), text);
+            assert_eq!(JMMessage::parse(&mut InputStream::new(text.as_bytes()), json).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn json_errors() {
+        let error = JMMessage::parse_json(&mut InputStream::new(b"{\"type\": \"Jump\"}")).unwrap_err();
+        assert!(error.to_string().starts_with("invalid JSON: unknown variant `Jump`"), "{}", error);
+
+        let error = JMMessage::parse_json(&mut InputStream::new(b"{\"type\":\n \"SetCell\", }")).unwrap_err();
+        assert_eq!(error.offset, 21);
+
+        let text = br#"{"type":"SetGrid","data":{"width":2,"height":2,"cells":[null]}}"#;
+        let error = JMMessage::parse_json(&mut InputStream::new(text)).unwrap_err();
+        assert!(error.to_string().contains("cell count does not match the grid size"), "{}", error);
+    }
+
+    #[test]
+    fn json_limits() {
+        let limits = DecodeLimits { max_string_length: 4, max_collection_length: 2, ..DecodeLimits::default() };
+        let parse = |text: &str| JMMessage::parse_json(&mut InputStream::with_limits(text.as_bytes(), limits)).map_err(|e| e.to_string());
+
+        assert!(parse(r#"{"type":"SetCell","data":[1,2,"push",0]}"#).is_ok());
+        assert_eq!(parse(r#"{"type":"SetCell","data":[1,2,"mover",0]}"#).unwrap_err(), "length 5 exceeds the limit of 4 at byte 0 while reading SetCell.id");
+        assert_eq!(
+            parse(r#"{"type":"Batch","data":[{"type":"SetCell","data":[1,2,"mover",0]}]}"#).unwrap_err(),
+            "length 5 exceeds the limit of 4 at byte 0 while reading Batch.messages.SetCell.id",
+        );
+
+        assert_eq!(
+            parse(r#"{"type":"SetGrid","data":{"width":1,"height":1,"cells":[["mover",0]]}}"#).unwrap_err(),
+            "length 5 exceeds the limit of 4 at byte 0 while reading SetGrid.grid.cells.id",
+        );
+        assert_eq!(
+            parse(r#"{"type":"SetGrid","data":{"width":3,"height":1,"cells":[null,null,null]}}"#).unwrap_err(),
+            "length 3 exceeds the limit of 2 at byte 0 while reading SetGrid.grid.cells",
+        );
+        assert_eq!(
+            parse(r#"{"type":"Batch","data":[{"type":"Delete","data":[1,2]},{"type":"Delete","data":[1,2]},{"type":"Delete","data":[1,2]}]}"#).unwrap_err(),
+            "length 3 exceeds the limit of 2 at byte 0 while reading Batch.messages",
+        );
+    }
+
+    #[test]
+    fn json_batches_are_flat() {
+        for inner in [r#"{"type":"Batch","data":[]}"#, r#"{"type":"GetGrid"}"#] {
+            let text = format!(r#"{{"type":"Batch","data":[{}]}}"#, inner);
+            let error = JMMessage::parse_json(&mut InputStream::new(text.as_bytes())).unwrap_err();
+            assert_eq!(error.to_string(), "batches can only contain SetCell and Delete at byte 0 while reading Batch.messages");
+        }
+
+        let text = format!(r#"{{"type":"Batch","data":[{}]}}"#, vec![r#"{"type":"Delete","data":[1,2]}"#; MAX_BATCH_LENGTH + 1].join(","));
+        let error = JMMessage::parse_json(&mut InputStream::new(text.as_bytes())).unwrap_err();
+        assert_eq!(error.to_string(), "length 4097 exceeds the limit of 4096 at byte 0 while reading Batch.messages");
+    }
+
+    #[test]
+    fn schema_reads_encoded_messages() {
+        let schema = Schema::of::<JMMessage>();
+        let mut grid = Grid::new(2, 1);
+        grid.cells[1] = Some(("mover".to_string(), 3));
+        let messages = [
+            (JMMessage::SetGrid(grid), serde_json::json!({ "SetGrid": { "grid": { "width": 2, "height": 1, "cells": [
+                { "id": "", "direction": 0 },
+                { "id": "mover", "direction": 3 },
+            ] } } })),
+            (JMMessage::SetCell(258, 3, "push".to_string(), 1), serde_json::json!({ "SetCell": { "x": 258, "y": 3, "id": "push", "direction": 1 } })),
+            (JMMessage::Delete(1, 2), serde_json::json!({ "Delete": { "x": 1, "y": 2 } })),
+        ];
+
+        for (message, expected) in messages {
+            let bytes = encode(&message);
+            let mut input = InputStream::new(&bytes);
+            assert_eq!(schema.read(&schema.root, &mut input).unwrap(), expected);
+            assert_eq!(input.remaining(), 0);
+        }
+    }
+
+    #[test]
+    fn set_grid_sizes() {
+        let sizes = |grid: &Grid| {
+            ["1", "2", "2.deflate", "2.zstd"].map(|name| {
+                let mut stream = OutputStream::new();
+                JMMessage::SetGrid(grid.clone()).write(&mut stream, Protocol::parse(name).unwrap());
+                stream.bytes.len()
+            })
+        };
+
+        let empty = Grid::new(100, 100);
+        let mut v1 = OutputStream::new();
+        v1.write(&empty);
+        assert_eq!(sizes(&empty)[0], v1.bytes.len() + 1);
+        // the whole grid is a single run
+        assert_eq!(sizes(&empty), [50009, 8, 10, 17]);
+
+        let mut sparse = Grid::new(100, 100);
+        for i in (0..10_000).step_by(37) {
+            sparse.cells[i] = Some((["mover", "push", "generator"][i % 3].to_string(), (i % 4) as u8));
+        }
+        let [v1, v2, deflate, zstd] = sizes(&sparse);
+        assert!(v2 * 10 < v1, "{} vs {}", v2, v1);
+        assert!(deflate < v2 && zstd < v2, "{} and {} vs {}", deflate, zstd, v2);
+    }
+}
+
+#[cfg(test)]
+mod roundtrip {
+    use proptest::prelude::*;
+
+    use crate::schema::Schema;
+
+    use super::*;
+
+    fn cell() -> impl Strategy<Value = Option<(String, u8)>> {
+        // an empty id is how empty cells are encoded
+        prop::option::of(("[a-zA-Z0-9_]{1,12}", any::<u8>()))
+    }
+
+    fn grid() -> impl Strategy<Value = Grid> {
+        (0u16..12, 0u16..12).prop_flat_map(|(width, height)| {
+            prop::collection::vec(cell(), width as usize * height as usize)
+                .prop_map(move |cells| Grid { width, height, cells })
+        })
+    }
+
+    fn cell_message() -> impl Strategy<Value = JMMessage> {
+        prop_oneof![
+            (any::<u16>(), any::<u16>(), "\\PC{0,16}", any::<u8>()).prop_map(|(x, y, id, dir)| JMMessage::SetCell(x, y, id, dir)),
+            (any::<u16>(), any::<u16>()).prop_map(|(x, y)| JMMessage::Delete(x, y)),
+        ]
+    }
+
+    fn v1_message() -> impl Strategy<Value = JMMessage> {
+        prop_oneof![
+            Just(JMMessage::GetGrid),
+            grid().prop_map(JMMessage::SetGrid),
+            cell_message(),
+        ]
+    }
+
+    fn message() -> impl Strategy<Value = JMMessage> {
+        prop_oneof![
+            v1_message(),
+            prop::collection::vec(cell_message(), 0..8).prop_map(JMMessage::Batch),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn message_roundtrip(message in v1_message()) {
+            let mut stream = OutputStream::new();
+            message.write_v1(&mut stream);
+            let mut input = InputStream::new(&stream.bytes);
+            prop_assert_eq!(JMMessage::parse_v1(&mut input).unwrap(), message);
+            prop_assert_eq!(input.remaining(), 0);
+        }
+
+        #[test]
+        fn message_roundtrip_v2(message in message(), name in prop::sample::select(vec!["2", "2.deflate", "2.zstd"])) {
+            let protocol = Protocol::parse(name).unwrap();
+            let mut stream = OutputStream::new();
+            message.write(&mut stream, protocol);
+            let mut input = InputStream::new(&stream.bytes);
+            prop_assert_eq!(JMMessage::parse(&mut input, protocol).unwrap(), message);
+            prop_assert_eq!(input.remaining(), 0);
+        }
+
+        #[test]
+        fn message_roundtrip_json(message in message()) {
+            let json = Protocol::parse("json").unwrap();
+            let mut stream = OutputStream::new();
+            message.write(&mut stream, json);
+            prop_assert_eq!(JMMessage::parse(&mut InputStream::new(&stream.bytes), json).unwrap(), message);
+        }
+
+        #[test]
+        fn schema_matches_encoder(message in v1_message()) {
+            let schema = Schema::of::<JMMessage>();
+            let mut stream = OutputStream::new();
+            message.write_v1(&mut stream);
+            let mut input = InputStream::new(&stream.bytes);
+            prop_assert!(schema.read(&schema.root, &mut input).is_ok());
+            prop_assert_eq!(input.remaining(), 0);
+        }
+
+        #[test]
+        fn grid_roundtrip(grid in grid()) {
+            let mut stream = OutputStream::new();
+            stream.write(&grid);
+            prop_assert_eq!(InputStream::new(&stream.bytes).read::<Grid>().unwrap(), grid);
+        }
+
+        #[test]
+        fn any_input_parses_or_errors(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+            let _ = JMMessage::parse_v1(&mut InputStream::new(&bytes));
+            let _ = JMMessage::parse(&mut InputStream::new(&bytes), Protocol::parse("2").unwrap());
         }
     }
+}
+
+#[cfg(test)]
+mod benches {
+    extern crate test;
+
+    use test::Bencher;
+
+    use super::*;
 
-    pub fn parse_v1(stream: &mut InputStream) -> Option<JMMessage> {
-        match stream.read::<u8>()? {
-            0 => Some(JMMessage::GetGrid),
-            1 => Some(JMMessage::SetGrid(stream.read::<Grid>()?)),
-            2 => Some(JMMessage::SetCell(
-                /*x*/ stream.read::<u16>()?,
-                /*y*/ stream.read::<u16>()?,
-                /*id*/ stream.read::<String>()?,
-                /*dir*/ stream.read::<u8>()?
-            )),
-            3 => Some(JMMessage::Delete(
-                stream.read::<u16>()?,
-                stream.read::<u16>()?
-            )),
-            _ => None
+    fn set_grid(filled: bool) -> Vec<u8> {
+        let mut grid = Grid::new(100, 100);
+        if filled {
+            for (i, cell) in grid.cells.iter_mut().enumerate() {
+                *cell = Some((format!("cell{}", i % 7), (i % 4) as u8));
+            }
         }
+        let mut stream = OutputStream::new();
+        JMMessage::SetGrid(grid).write_v1(&mut stream);
+        stream.bytes
+    }
+
+    #[bench]
+    fn parse_empty_grid_100x100(b: &mut Bencher) {
+        let bytes = set_grid(false);
+        b.iter(|| JMMessage::parse_v1(&mut InputStream::new(&bytes)).unwrap());
+    }
+
+    #[bench]
+    fn parse_filled_grid_100x100(b: &mut Bencher) {
+        let bytes = set_grid(true);
+        b.iter(|| JMMessage::parse_v1(&mut InputStream::new(&bytes)).unwrap());
     }
 }
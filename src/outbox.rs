@@ -0,0 +1,107 @@
+use std::collections::{hash_map::Entry, HashMap};
+
+use jell_machine_server::{messages::{JMMessage, MAX_BATCH_LENGTH}, protocol::{Protocol, Version}};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::server::encode;
+
+/// Cell changes waiting to be sent to a client on the next tick.
+/// A later write to a cell replaces the pending one for the same cell.
+#[derive(Default)]
+pub struct Outbox {
+    messages: Vec<JMMessage>,
+    cells: HashMap<(u16, u16), usize>,
+}
+
+impl Outbox {
+    pub fn push(&mut self, x: u16, y: u16, id: String, direction: u8) {
+        let message = JMMessage::SetCell(x, y, id, direction);
+        match self.cells.entry((x, y)) {
+            Entry::Occupied(entry) => self.messages[*entry.get()] = message,
+            Entry::Vacant(entry) => {
+                entry.insert(self.messages.len());
+                self.messages.push(message);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Encodes everything pending into frames and empties the outbox.
+    /// v2 and JSON clients get `Batch`es of up to `MAX_BATCH_LENGTH`
+    /// messages, v1 clients don't know batches and get one frame per message.
+    pub fn flush(&mut self, protocol: Protocol) -> Vec<Message> {
+        self.cells.clear();
+        let mut messages = std::mem::take(&mut self.messages);
+        if protocol.version != Version::V1 && messages.len() > 1 {
+            messages = messages.chunks(MAX_BATCH_LENGTH)
+                .map(|chunk| JMMessage::batch(chunk.to_vec()).expect("short runs of cell changes are valid batches"))
+                .collect();
+        }
+
+        messages.iter().map(|message| encode(message, protocol)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jell_machine_server::{binary_io::InputStream, protocol::Compression};
+
+    use super::*;
+
+    fn set_cell(x: u16, y: u16, id: &str) -> JMMessage {
+        JMMessage::SetCell(x, y, id.to_string(), 0)
+    }
+
+    fn push(outbox: &mut Outbox, x: u16, y: u16, id: &str) {
+        outbox.push(x, y, id.to_string(), 0);
+    }
+
+    #[test]
+    fn repeated_writes_collapse() {
+        let v2 = Protocol { version: Version::V2, compression: Compression::None };
+        let mut outbox = Outbox::default();
+        push(&mut outbox, 1, 1, "mover");
+        push(&mut outbox, 2, 1, "push");
+        push(&mut outbox, 1, 1, "generator");
+        push(&mut outbox, 2, 1, "");
+
+        let frames = outbox.flush(v2);
+        assert!(outbox.is_empty());
+        assert_eq!(frames.len(), 1);
+        let batch = JMMessage::parse(&mut InputStream::new(&frames[0].clone().into_data()), v2).unwrap();
+        assert_eq!(batch, JMMessage::Batch(vec![set_cell(1, 1, "generator"), set_cell(2, 1, "")]));
+
+        // nothing from before the flush is collapsed into later writes
+        push(&mut outbox, 1, 1, "mover");
+        assert_eq!(outbox.flush(v2).len(), 1);
+        assert!(outbox.flush(v2).is_empty());
+    }
+
+    #[test]
+    fn long_batches_are_split() {
+        let v2 = Protocol { version: Version::V2, compression: Compression::None };
+        let mut outbox = Outbox::default();
+        for x in 0..MAX_BATCH_LENGTH as u16 + 1 {
+            push(&mut outbox, x, 0, "mover");
+        }
+
+        let frames = outbox.flush(v2);
+        assert_eq!(frames.len(), 2);
+        let last = JMMessage::parse(&mut InputStream::new(&frames[1].clone().into_data()), v2).unwrap();
+        assert_eq!(last, JMMessage::Batch(vec![set_cell(MAX_BATCH_LENGTH as u16, 0, "mover")]));
+    }
+
+    #[test]
+    fn v1_gets_single_messages() {
+        let mut outbox = Outbox::default();
+        push(&mut outbox, 1, 1, "mover");
+        push(&mut outbox, 2, 2, "mover");
+
+        let frames = outbox.flush(Protocol::V1);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(JMMessage::parse_v1(&mut InputStream::new(&frames[1].clone().into_data())).unwrap(), set_cell(2, 2, "mover"));
+    }
+}
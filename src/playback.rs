@@ -0,0 +1,207 @@
+use std::{fs::File, io::{self, BufWriter, Write}, path::Path, sync::Arc, time::{Duration, Instant}};
+
+use futures::future;
+use tokio::{sync::watch, time};
+
+use jell_machine_server::{binary_io::OutputStream, grid::Grid, messages::JMMessage, replay};
+
+use crate::{log::{LogRecord, Level, Category, Logger}, server::{set_cell, State}, shutdown::Shutdown, writer::{Writer, Sink}};
+
+/// Fastest playback `/speed` and `--speed` accept.
+pub const MAX_SPEED: f64 = 10000.0;
+/// Slowest playback they accept besides 0, which pauses.
+pub const MIN_SPEED: f64 = 0.001;
+
+pub fn valid_speed(speed: f64) -> bool {
+    speed == 0.0 || (MIN_SPEED..=MAX_SPEED).contains(&speed)
+}
+
+/// Creates the replay file, starting with `grid`.
+pub fn create(path: &Path, grid: &Grid) -> io::Result<File> {
+    let mut file = File::create(path)?;
+    let mut stream = OutputStream::new();
+    replay::write_header(&mut stream, grid);
+    file.write_all(&stream.bytes)?;
+    Ok(file)
+}
+
+/// Appends every applied message to the replay file with the time since
+/// recording started. Frames are written by a thread of their own, which
+/// flushes the file once it has caught up with them.
+#[derive(Clone)]
+pub struct Recorder {
+    writer: Writer<(Duration, JMMessage)>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn new(file: File, log: Logger) -> Self {
+        let writer = Writer::new(ReplayFile(BufWriter::new(file)), move |e| {
+            log.log(LogRecord::new(Level::Error, Category::Server, None, format!("Error writing replay: {}", e)));
+        });
+        Self { writer, start: Instant::now() }
+    }
+
+    pub fn record(&self, message: JMMessage) {
+        self.writer.send((self.start.elapsed(), message));
+    }
+
+    /// Stops taking messages and waits until the queued ones are written.
+    pub async fn finish(&self) {
+        self.writer.finish().await;
+    }
+}
+
+/// Frames after the header `create` wrote.
+struct ReplayFile(BufWriter<File>);
+
+impl Sink<(Duration, JMMessage)> for ReplayFile {
+    fn write(&mut self, (time, message): (Duration, JMMessage)) -> io::Result<()> {
+        let mut stream = OutputStream::new();
+        replay::write_frame(&mut stream, time, &message);
+        self.0.write_all(&stream.bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// How fast a replay is played, 1 is as fast as it was recorded and 0 pauses it.
+#[derive(Clone)]
+pub struct Playback {
+    speed: Arc<watch::Sender<f64>>,
+}
+
+impl Playback {
+    pub fn new(speed: f64) -> Self {
+        Self { speed: Arc::new(watch::channel(speed).0) }
+    }
+
+    pub fn speed(&self) -> f64 {
+        *self.speed.borrow()
+    }
+
+    pub fn set_speed(&self, speed: f64) {
+        self.speed.send_replace(speed);
+    }
+}
+
+/// Applies the frames to the grid at the pace of `playback`, clients get them
+/// with the next tick like edits of another client.
+pub async fn play(frames: Vec<(Duration, JMMessage)>, playback: Playback, state: State) {
+    let shutdown = state.shutdown.clone();
+    if play_frames(frames, playback, shutdown, |message| apply(&state, message)).await {
+        state.log.log(LogRecord::new(Level::Info, Category::Server, None, "Replay finished.".to_string()));
+    }
+}
+
+/// Passes each frame to `apply` once its time has come, returns whether all
+/// of them were played before the shutdown.
+async fn play_frames(frames: Vec<(Duration, JMMessage)>, playback: Playback, shutdown: Shutdown, mut apply: impl FnMut(JMMessage)) -> bool {
+    let mut speed = playback.speed.subscribe();
+    // how far into the replay playing has got
+    let mut position = Duration::ZERO;
+
+    for (time, message) in frames {
+        while position < time {
+            let rate = *speed.borrow_and_update();
+            let started = time::Instant::now();
+            let wait = async {
+                match wait_for(time - position, rate) {
+                    Some(wait) => time::sleep(wait).await,
+                    None => future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = wait => position = time,
+                changed = speed.changed() => {
+                    if changed.is_err() {
+                        return false;
+                    }
+                    position = (position + started.elapsed().mul_f64(rate)).min(time);
+                }
+                _ = shutdown.wait() => return false,
+            }
+        }
+        apply(message);
+    }
+    true
+}
+
+/// How long playing `left` takes at `rate`, `None` when it is paused or
+/// would take longer than a `Duration` can hold.
+fn wait_for(left: Duration, rate: f64) -> Option<Duration> {
+    match rate > 0.0 {
+        true => Duration::try_from_secs_f64(left.as_secs_f64() / rate).ok(),
+        false => None,
+    }
+}
+
+fn apply(state: &State, message: JMMessage) {
+    match message {
+        JMMessage::SetCell(x, y, cell_id, direction) => {
+            set_cell(state, x, y, cell_id, direction, None);
+        }
+        JMMessage::Batch(messages) => {
+            for message in messages {
+                apply(state, message);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn speeds() {
+        assert_eq!(wait_for(Duration::from_secs(3), 2.0), Some(Duration::from_millis(1500)));
+        assert_eq!(wait_for(Duration::from_secs(3), 0.0), None);
+        assert_eq!(wait_for(Duration::MAX, 1e-300), None);
+
+        assert!(valid_speed(0.0) && valid_speed(MIN_SPEED) && valid_speed(MAX_SPEED));
+        for speed in [1e-300, -1.0, MAX_SPEED * 2.0, f64::NAN] {
+            assert!(!valid_speed(speed));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn playing() {
+        let frames = (1..=4).map(|x| (Duration::from_secs(10 * x as u64), JMMessage::SetCell(x, 0, "mover".to_string(), 0))).collect();
+        let (playback, shutdown) = (Playback::new(1.0), Shutdown::new());
+        let start = time::Instant::now();
+        let applied = Arc::new(Mutex::new(Vec::new()));
+        let player = tokio::spawn(play_frames(frames, playback.clone(), shutdown.clone(), {
+            let applied = applied.clone();
+            move |message| {
+                if let JMMessage::SetCell(x, ..) = message {
+                    applied.lock().unwrap().push((x, start.elapsed()));
+                }
+            }
+        }));
+
+        // halfway to the second frame it plays twice as fast
+        time::sleep_until(start + Duration::from_secs(15)).await;
+        playback.set_speed(2.0);
+        // a quarter of the way to the third frame it pauses
+        time::sleep_until(start + Duration::from_secs(20)).await;
+        playback.set_speed(0.0);
+        time::sleep_until(start + Duration::from_secs(100)).await;
+        assert_eq!(applied.lock().unwrap().len(), 2);
+        playback.set_speed(1.0);
+        // the fourth frame is due at 115s
+        time::sleep_until(start + Duration::from_secs(110)).await;
+        shutdown.trigger();
+
+        assert!(!player.await.unwrap());
+        assert_eq!(*applied.lock().unwrap(), [(1, Duration::from_secs(10)), (2, Duration::from_millis(17500)), (3, Duration::from_secs(105))]);
+
+        let frames = vec![(Duration::ZERO, JMMessage::GetGrid)];
+        assert!(play_frames(frames, playback, Shutdown::new(), |_| {}).await);
+    }
+}
@@ -0,0 +1,168 @@
+use std::{fmt, io::{Read, Write}, borrow::Cow};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+
+use crate::binary_io::DecodeErrorKind;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    /// Fixed size integers and length prefixes.
+    V1,
+    /// Varint length prefixes and run-length encoded grids.
+    V2,
+    /// Messages as JSON in text frames, for debugging and simple clients.
+    Json,
+}
+
+/// How `SetGrid` payloads are compressed, only supported from v2 on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// Raw DEFLATE (RFC 1951) without zlib header.
+    Deflate,
+    Zstd,
+}
+
+/// What a client speaks, negotiated through the `Sec-WebSocket-Protocol`
+/// header. The names are the version optionally followed by the
+/// compression, e.g. `1`, `2` or `2.zstd`, or `json`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protocol {
+    pub version: Version,
+    pub compression: Compression,
+}
+
+impl Protocol {
+    pub const V1: Protocol = Protocol { version: Version::V1, compression: Compression::None };
+
+    pub fn parse(name: &str) -> Option<Protocol> {
+        let (version, compression) = match name.split_once('.') {
+            Some((version, compression)) => (version, Some(compression)),
+            None => (name, None),
+        };
+        let version = match version {
+            "1" => Version::V1,
+            "2" => Version::V2,
+            "json" => Version::Json,
+            _ => return None,
+        };
+        let compression = match (version, compression) {
+            (_, None) => Compression::None,
+            (Version::V2, Some("deflate")) => Compression::Deflate,
+            (Version::V2, Some("zstd")) => Compression::Zstd,
+            _ => return None,
+        };
+        Some(Protocol { version, compression })
+    }
+
+    /// Picks the first supported protocol from a comma separated list,
+    /// clients list the ones they prefer first.
+    pub fn negotiate(offered: &str) -> Option<Protocol> {
+        offered.split(',').map(str::trim).find_map(Protocol::parse)
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.version {
+            Version::V1 => write!(f, "1")?,
+            Version::V2 => write!(f, "2")?,
+            Version::Json => write!(f, "json")?,
+        }
+        match self.compression {
+            Compression::None => Ok(()),
+            Compression::Deflate => write!(f, ".deflate"),
+            Compression::Zstd => write!(f, ".zstd"),
+        }
+    }
+}
+
+impl Compression {
+    pub fn compress(self, bytes: Vec<u8>) -> Vec<u8> {
+        match self {
+            Compression::None => bytes,
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&bytes).unwrap();
+                encoder.finish().unwrap()
+            },
+            Compression::Zstd => zstd::encode_all(bytes.as_slice(), 0).unwrap(),
+        }
+    }
+
+    /// Fails instead of producing more than `max_size` bytes, so a small
+    /// payload can never expand into a huge allocation. zstd frames that ask
+    /// for a window larger than `max_size` needs are refused as well.
+    pub fn decompress(self, bytes: &[u8], max_size: usize) -> Result<Cow<'_, [u8]>, DecodeErrorKind> {
+        let corrupt = |_| DecodeErrorKind::Invalid("corrupt compressed data");
+        let decoder: Box<dyn Read> = match self {
+            Compression::None => return Ok(Cow::Borrowed(bytes)),
+            Compression::Deflate => Box::new(DeflateDecoder::new(bytes)),
+            Compression::Zstd => {
+                let mut decoder = zstd::Decoder::with_buffer(bytes).map_err(corrupt)?;
+                decoder.window_log_max(zstd_window_log(max_size)).map_err(corrupt)?;
+                Box::new(decoder)
+            },
+        };
+
+        let mut data = Vec::new();
+        decoder.take(max_size as u64 + 1).read_to_end(&mut data).map_err(corrupt)?;
+        if data.len() > max_size {
+            return Err(DecodeErrorKind::Invalid("decompressed data is larger than the message size limit"));
+        }
+        Ok(Cow::Owned(data))
+    }
+}
+
+/// The smallest window that holds `max_size` bytes. At least 8 MiB, the most
+/// streaming encoders pick below the ultra levels before they know the size,
+/// and at most the 128 MiB zstd decoders allow by default.
+fn zstd_window_log(max_size: usize) -> u32 {
+    max_size.checked_next_power_of_two().map_or(usize::BITS, usize::trailing_zeros).clamp(23, 27)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        for name in ["1", "2", "2.deflate", "2.zstd", "json"] {
+            assert_eq!(Protocol::parse(name).unwrap().to_string(), name);
+        }
+        for name in ["", "3", "1.zstd", "2.gzip", "2.", "json.zstd"] {
+            assert_eq!(Protocol::parse(name), None, "{:?}", name);
+        }
+
+        let zstd = Protocol { version: Version::V2, compression: Compression::Zstd };
+        assert_eq!(Protocol::negotiate("3, 2.zstd, 1"), Some(zstd));
+        assert_eq!(Protocol::negotiate("1"), Some(Protocol::V1));
+        assert_eq!(Protocol::negotiate("chat, 4"), None);
+    }
+
+    #[test]
+    fn compression() {
+        let data = vec![7u8; 10_000];
+        for compression in [Compression::None, Compression::Deflate, Compression::Zstd] {
+            let compressed = compression.compress(data.clone());
+            assert_eq!(compression.decompress(&compressed, data.len()).unwrap(), data.as_slice());
+            if compression != Compression::None {
+                assert!(compressed.len() < 100, "{:?}: {} bytes", compression, compressed.len());
+                assert!(compression.decompress(&compressed, data.len() - 1).is_err());
+                assert!(compression.decompress(&compressed[..compressed.len() / 2], data.len()).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn zstd_window_is_limited() {
+        let mut encoder = zstd::Encoder::new(Vec::new(), 0).unwrap();
+        encoder.window_log(27).unwrap();
+        encoder.write_all(&[7u8; 100]).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        assert!(Compression::Zstd.decompress(&compressed, 128 << 20).is_ok());
+        assert_eq!(Compression::Zstd.decompress(&compressed, 16 << 20), Err(DecodeErrorKind::Invalid("corrupt compressed data")));
+        assert_eq!((zstd_window_log(0), zstd_window_log(16 << 20), zstd_window_log(usize::MAX)), (23, 24, 27));
+    }
+}
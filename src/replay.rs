@@ -0,0 +1,107 @@
+//! Recordings of a session for timelapses: the grid at the start and every
+//! message applied after it with the time it was applied at.
+//!
+//! A replay file starts with [`MAGIC`] and the grid the way protocol v2
+//! encodes it. Every frame after that is the time since the start in
+//! milliseconds as a varint, the length of the message as a varint and the
+//! message encoded with protocol v2 without compression.
+use std::{fs, io, path::Path, time::Duration};
+
+use crate::{binary_io::{InputStream, OutputStream, DecodeError, DecodeErrorKind, DecodeLimits, Context, VarU32, VarU64}, grid::Grid, messages::JMMessage, protocol::Compression};
+
+pub const MAGIC: &[u8] = b"JMREPLAY1";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replay {
+    pub grid: Grid,
+    /// Oldest first.
+    pub frames: Vec<(Duration, JMMessage)>,
+}
+
+/// The start of a replay file.
+pub fn write_header(stream: &mut OutputStream, grid: &Grid) {
+    stream.write_bytes(MAGIC);
+    grid.write_v2(stream);
+}
+
+pub fn write_frame(stream: &mut OutputStream, time: Duration, message: &JMMessage) {
+    let mut payload = OutputStream::new();
+    message.write_v2(&mut payload, Compression::None);
+    stream.write(VarU64(time.as_millis() as u64));
+    stream.write(VarU32(payload.bytes.len() as u32));
+    stream.write_bytes(&payload.bytes);
+}
+
+impl Replay {
+    /// A frame that is cut off at the end, as after a crash while recording, is left out.
+    pub fn read(bytes: &[u8]) -> Result<Self, DecodeError> {
+        // replays can hold larger grids than clients are allowed to send
+        let limits = DecodeLimits {
+            max_collection_length: usize::MAX,
+            max_message_size: usize::MAX,
+            ..DecodeLimits::default()
+        };
+        let mut stream = InputStream::with_limits(bytes, limits);
+        if !bytes.starts_with(MAGIC) {
+            return Err(stream.error(DecodeErrorKind::Invalid("not a replay file")));
+        }
+        stream.read_bytes(MAGIC.len())?;
+        let grid = Grid::read_v2(&mut stream).context("grid")?;
+
+        let mut frames = Vec::new();
+        while stream.remaining() > 0 {
+            let Some((time, payload)) = read_frame(&mut stream) else { break };
+            let message = JMMessage::parse_v2(&mut InputStream::with_limits(payload, limits), Compression::None).context("frame")?;
+            frames.push((Duration::from_millis(time), message));
+        }
+        Ok(Replay { grid, frames })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Replay::read(&fs::read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// `None` when the frame doesn't fit into what is left.
+fn read_frame<'a>(stream: &mut InputStream<'a>) -> Option<(u64, &'a [u8])> {
+    let time = stream.read::<VarU64>().ok()?.0;
+    let length = stream.read::<VarU32>().ok()?.0;
+    let payload = stream.read_bytes(length as usize).ok()?;
+    Some((time, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recording() -> (Grid, Vec<u8>) {
+        let mut grid = Grid::new(300, 200);
+        *grid.get(4, 5) = Some(("mover".to_string(), 2));
+        let mut stream = OutputStream::new();
+        write_header(&mut stream, &grid);
+        write_frame(&mut stream, Duration::from_millis(0), &JMMessage::SetCell(1, 2, "push".to_string(), 1));
+        write_frame(&mut stream, Duration::from_millis(90_061), &JMMessage::SetCell(1, 2, String::new(), 0));
+        (grid, stream.bytes)
+    }
+
+    #[test]
+    fn roundtrip() {
+        let (grid, bytes) = recording();
+        let replay = Replay::read(&bytes).unwrap();
+        assert_eq!(replay.grid, grid);
+        assert_eq!(replay.frames, [
+            (Duration::ZERO, JMMessage::SetCell(1, 2, "push".to_string(), 1)),
+            (Duration::from_millis(90_061), JMMessage::SetCell(1, 2, String::new(), 0)),
+        ]);
+    }
+
+    #[test]
+    fn cut_off_frames_are_left_out() {
+        let (_, bytes) = recording();
+        let replay = Replay::read(&bytes[..bytes.len() - 3]).unwrap();
+        assert_eq!(replay.frames.len(), 1);
+
+        assert!(Replay::read(b"JMREPLAY").is_err());
+        assert!(Replay::read(&bytes[..MAGIC.len() + 3]).is_err());
+    }
+}
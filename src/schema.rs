@@ -0,0 +1,413 @@
+//! A machine-readable description of the binary encoding, generated from the
+//! `IOAble` impls so client authors don't have to read the Rust code.
+//! `docs/protocol.md` and `docs/protocol.json` are rendered from it. It only
+//! covers v1, v2 and JSON are encoded by hand and `docs/protocol.md` describes
+//! them by how they differ, with examples the tests encode.
+
+use std::fmt::{self, Write};
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::binary_io::{InputStream, DecodeError, DecodeErrorKind, Context, VarU32, VarU64, VarI32, VarI64, CompactString, CompactVec};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Type {
+    /// Big-endian, two's complement if signed.
+    Integer { bits: u8, signed: bool },
+    /// Big-endian IEEE 754.
+    Float { bits: u8 },
+    /// One byte, anything but 0 is true.
+    Bool,
+    /// LEB128, zigzag encoded first if signed.
+    Varint { bits: u8, signed: bool },
+    /// UTF-8 bytes after their length.
+    String { length: Box<Type> },
+    /// The number of items followed by the items.
+    List { length: Box<Type>, item: Box<Type> },
+    /// A bool followed by the value if it is true.
+    Option { item: Box<Type> },
+    /// A struct or enum from the definitions.
+    Ref { name: &'static str },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Field {
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub ty: Type,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    pub doc: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Variant {
+    pub name: &'static str,
+    pub tag: u8,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    pub doc: &'static str,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Definition {
+    /// The fields one after another.
+    Struct { name: &'static str, #[serde(skip_serializing_if = "str::is_empty")] doc: &'static str, fields: Vec<Field> },
+    /// A `u8` tag followed by the fields of the variant with that tag.
+    Enum { name: &'static str, #[serde(skip_serializing_if = "str::is_empty")] doc: &'static str, variants: Vec<Variant> },
+}
+
+impl Definition {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Definition::Struct { name, .. } | Definition::Enum { name, .. } => name,
+        }
+    }
+}
+
+/// Implemented by `#[derive(IOAble)]`, describes how a type is encoded.
+pub trait Describe {
+    fn describe(schema: &mut Schema) -> Type;
+}
+
+/// All structs and enums reachable from one root type, in the order they
+/// were first referenced.
+#[derive(Debug, Clone, Serialize)]
+pub struct Schema {
+    /// Of all integers and floats, always `big`.
+    pub endianness: &'static str,
+    pub root: Type,
+    pub definitions: Vec<Definition>,
+    #[serde(skip)]
+    started: Vec<&'static str>,
+}
+
+impl Schema {
+    pub fn of<T>() -> Self where T: Describe {
+        let mut schema = Schema { endianness: "big", root: Type::Bool, definitions: Vec::new(), started: Vec::new() };
+        schema.root = T::describe(&mut schema);
+        let order = std::mem::take(&mut schema.started);
+        schema.definitions.sort_by_key(|definition| order.iter().position(|name| *name == definition.name()));
+        schema
+    }
+
+    /// Returns false if `name` was already described, recursive types stop here.
+    pub fn start(&mut self, name: &'static str) -> bool {
+        if self.started.contains(&name) {
+            return false;
+        }
+        self.started.push(name);
+        true
+    }
+
+    pub fn define(&mut self, definition: Definition) {
+        self.definitions.push(definition);
+    }
+
+    pub fn definition(&self, name: &str) -> Option<&Definition> {
+        self.definitions.iter().find(|definition| definition.name() == name)
+    }
+
+    /// Reads a value of type `ty` only by following the schema. Tests use this
+    /// to check that the schema matches the encoder, tools to inspect messages.
+    pub fn read(&self, ty: &Type, stream: &mut InputStream) -> Result<Value, DecodeError> {
+        Ok(match ty {
+            Type::Integer { .. } | Type::Varint { .. } => json!(self.read_integer(ty, stream)?),
+            Type::Float { bits: 32 } => json!(stream.read::<f32>()?),
+            Type::Float { .. } => json!(stream.read::<f64>()?),
+            Type::Bool => json!(stream.read::<bool>()?),
+            Type::String { length } => {
+                let start = stream.position();
+                let length = self.read_length(length, stream)?;
+                let bytes = stream.read_bytes(length)?;
+                let text = std::str::from_utf8(bytes).map_err(|e| DecodeError::new(start + e.valid_up_to(), DecodeErrorKind::InvalidUtf8))?;
+                json!(text)
+            },
+            Type::List { length, item } => {
+                let length = self.read_length(length, stream)?;
+                let mut items = Vec::new();
+                for _ in 0..length {
+                    items.push(self.read(item, stream)?);
+                }
+                Value::Array(items)
+            },
+            Type::Option { item } => match stream.read::<bool>()? {
+                true => self.read(item, stream)?,
+                false => Value::Null,
+            },
+            Type::Ref { name } => match self.definition(name) {
+                Some(Definition::Struct { fields, .. }) => self.read_fields(fields, stream)?,
+                Some(Definition::Enum { variants, .. }) => {
+                    let start = stream.position();
+                    let tag = stream.read::<u8>().context("tag")?;
+                    let variant = variants.iter().find(|variant| variant.tag == tag)
+                        .ok_or_else(|| DecodeError::new(start, DecodeErrorKind::UnknownTag(tag))).context("tag")?;
+                    let fields = self.read_fields(&variant.fields, stream).context(variant.name)?;
+                    json!({ variant.name: fields })
+                },
+                None => return Err(stream.error(DecodeErrorKind::Invalid("type is missing from the schema"))),
+            },
+        })
+    }
+
+    fn read_fields(&self, fields: &[Field], stream: &mut InputStream) -> Result<Value, DecodeError> {
+        let mut object = Map::new();
+        for field in fields {
+            object.insert(field.name.to_string(), self.read(&field.ty, stream).context(field.name)?);
+        }
+        Ok(Value::Object(object))
+    }
+
+    fn read_integer(&self, ty: &Type, stream: &mut InputStream) -> Result<i128, DecodeError> {
+        Ok(match *ty {
+            Type::Integer { bits: 8, signed } => if signed { stream.read::<i8>()? as i128 } else { stream.read::<u8>()? as i128 },
+            Type::Integer { bits: 16, signed } => if signed { stream.read::<i16>()? as i128 } else { stream.read::<u16>()? as i128 },
+            Type::Integer { bits: 32, signed } => if signed { stream.read::<i32>()? as i128 } else { stream.read::<u32>()? as i128 },
+            Type::Integer { bits: 64, signed } => if signed { stream.read::<i64>()? as i128 } else { stream.read::<u64>()? as i128 },
+            Type::Varint { bits, signed } => {
+                let value = stream.read_varint(bits as u32)?;
+                if signed { (value >> 1) as i128 ^ -((value & 1) as i128) } else { value as i128 }
+            },
+            _ => return Err(stream.error(DecodeErrorKind::Invalid("not an integer type"))),
+        })
+    }
+
+    fn read_length(&self, ty: &Type, stream: &mut InputStream) -> Result<usize, DecodeError> {
+        let start = stream.position();
+        let length = self.read_integer(ty, stream)?;
+        if length < 0 {
+            return Err(DecodeError::new(start, DecodeErrorKind::NegativeLength(length as i32)));
+        }
+        stream.check_collection_length(start, length as u64)
+    }
+
+    /// Renders the schema as a markdown document with one section per definition.
+    pub fn to_markdown(&self, title: &str, intro: &str) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# {}\n\n{}\n", title, intro);
+        let _ = writeln!(out, "Every frame holds one {}. All integers are big-endian.\n", self.root);
+
+        for definition in &self.definitions {
+            match definition {
+                Definition::Struct { name, doc, fields } => {
+                    let _ = writeln!(out, "## {}\n", name);
+                    write_doc(&mut out, doc);
+                    write_fields(&mut out, fields);
+                },
+                Definition::Enum { name, doc, variants } => {
+                    let _ = writeln!(out, "## {}\n", name);
+                    write_doc(&mut out, doc);
+                    let _ = writeln!(out, "Starts with a `u8` tag that selects the variant.\n");
+                    let _ = writeln!(out, "| Tag | Variant |\n|---|---|");
+                    for variant in variants {
+                        let _ = writeln!(out, "| {} | [{}](#{}-{}) |", variant.tag, variant.name, variant.tag, variant.name.to_lowercase());
+                    }
+                    let _ = writeln!(out);
+                    for variant in variants {
+                        let _ = writeln!(out, "### {} {}\n", variant.tag, variant.name);
+                        write_doc(&mut out, variant.doc);
+                        write_fields(&mut out, &variant.fields);
+                    }
+                },
+            }
+        }
+        out
+    }
+}
+
+fn write_doc(out: &mut String, doc: &str) {
+    if !doc.is_empty() {
+        let _ = writeln!(out, "{}\n", doc);
+    }
+}
+
+fn write_fields(out: &mut String, fields: &[Field]) {
+    if fields.is_empty() {
+        let _ = writeln!(out, "No fields.\n");
+        return;
+    }
+    if fields.iter().all(|field| field.doc.is_empty()) {
+        let _ = writeln!(out, "| Field | Type |\n|---|---|");
+        for field in fields {
+            let _ = writeln!(out, "| {} | {} |", field.name, field.ty);
+        }
+    }
+    else {
+        let _ = writeln!(out, "| Field | Type | |\n|---|---|---|");
+        for field in fields {
+            let _ = writeln!(out, "| {} | {} | {} |", field.name, field.ty, field.doc.replace('\n', " "));
+        }
+    }
+    let _ = writeln!(out);
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Integer { bits, signed } => write!(f, "`{}{}`", if *signed { "i" } else { "u" }, bits),
+            Type::Float { bits } => write!(f, "`f{}`", bits),
+            Type::Bool => write!(f, "`bool`"),
+            Type::Varint { bits, signed: false } => write!(f, "varint `u{}`", bits),
+            Type::Varint { bits, signed: true } => write!(f, "zigzag varint `i{}`", bits),
+            Type::String { length } => write!(f, "UTF-8 string with {} length", length),
+            Type::List { length, item } => write!(f, "list of {} with {} length", item, length),
+            Type::Option { item } => write!(f, "optional {}", item),
+            Type::Ref { name } => write!(f, "[{}](#{})", name, name.to_lowercase()),
+        }
+    }
+}
+
+macro_rules! describe_primitive {
+    ($($ty:ty => $description:expr),* $(,)?) => {
+        $(
+            impl Describe for $ty {
+                fn describe(_: &mut Schema) -> Type {
+                    $description
+                }
+            }
+        )*
+    };
+}
+
+describe_primitive! {
+    i8 => Type::Integer { bits: 8, signed: true },
+    u8 => Type::Integer { bits: 8, signed: false },
+    i16 => Type::Integer { bits: 16, signed: true },
+    u16 => Type::Integer { bits: 16, signed: false },
+    i32 => Type::Integer { bits: 32, signed: true },
+    u32 => Type::Integer { bits: 32, signed: false },
+    i64 => Type::Integer { bits: 64, signed: true },
+    u64 => Type::Integer { bits: 64, signed: false },
+    f32 => Type::Float { bits: 32 },
+    f64 => Type::Float { bits: 64 },
+    bool => Type::Bool,
+    String => Type::String { length: Box::new(Type::Integer { bits: 32, signed: false }) },
+    VarU32 => Type::Varint { bits: 32, signed: false },
+    VarU64 => Type::Varint { bits: 64, signed: false },
+    VarI32 => Type::Varint { bits: 32, signed: true },
+    VarI64 => Type::Varint { bits: 64, signed: true },
+    CompactString => Type::String { length: Box::new(Type::Varint { bits: 32, signed: false }) },
+}
+
+impl<T> Describe for Vec<T> where T: Describe {
+    fn describe(schema: &mut Schema) -> Type {
+        Type::List { length: Box::new(Type::Integer { bits: 32, signed: true }), item: Box::new(T::describe(schema)) }
+    }
+}
+
+impl<T> Describe for CompactVec<T> where T: Describe {
+    fn describe(schema: &mut Schema) -> Type {
+        Type::List { length: Box::new(Type::Varint { bits: 32, signed: false }), item: Box::new(T::describe(schema)) }
+    }
+}
+
+impl<T> Describe for Option<T> where T: Describe {
+    fn describe(schema: &mut Schema) -> Type {
+        Type::Option { item: Box::new(T::describe(schema)) }
+    }
+}
+
+impl<T> Describe for &T where T: Describe {
+    fn describe(schema: &mut Schema) -> Type {
+        T::describe(schema)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf};
+
+    use crate::{binary_io::OutputStream, grid::Grid, messages::JMMessage, protocol::Protocol};
+
+    use super::*;
+
+    const TITLE: &str = "Jell Machine protocol";
+    const INTRO: &str = "Generated from the Rust definitions by `cargo test`, run it with `UPDATE_SCHEMA=1` after changing them.\n\
+        Clients pick the version in the `Sec-WebSocket-Protocol` header. The tables describe version `1`, as does `protocol.json`,\n\
+        [version 2](#version-2) and [JSON](#json) are described at the end by how they differ from it.";
+
+    /// The sections about v2 and JSON. Their examples are encoded here, so
+    /// changing either encoding changes the document and fails the test.
+    fn other_versions() -> String {
+        let mut grid = Grid::new(3, 1);
+        grid.cells[2] = Some(("mover".to_string(), 3));
+        let set_grid = JMMessage::SetGrid(grid);
+        let batch = JMMessage::Batch(vec![JMMessage::SetCell(1, 2, "push".to_string(), 1), JMMessage::Delete(1, 2)]);
+
+        let encode = |message: &JMMessage, name: &str| {
+            let protocol = Protocol::parse(name).unwrap();
+            let mut stream = OutputStream::new();
+            message.write(&mut stream, protocol);
+            assert_eq!(&JMMessage::parse(&mut InputStream::new(&stream.bytes), protocol).unwrap(), message);
+            stream.bytes
+        };
+        let hex = |message: &JMMessage| encode(message, "2").iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ");
+        let json = |message: &JMMessage| String::from_utf8(encode(message, "json")).unwrap();
+
+        format!(
+"## Version 2
+
+Offered as `2`, `2.deflate` or `2.zstd`. Tags, fields and their order are the same as in version 1, but
+
+- there is one more message, `Batch` with tag 4: several messages in one frame that are handled in order,
+  a varint `u32` count followed by at most 4096 messages, only `SetCell` and `Delete`,
+- strings have a varint `u32` length, varints are unsigned LEB128 in their shortest form,
+- the grid of `SetGrid` is `width` and `height` as `u16` followed by runs of identical cells until the grid is full:
+  a varint `u32` run length, the id as a string and the direction as `u8`, which is left out for empty cells,
+- with `.deflate` or `.zstd` everything after the tag of `SetGrid` is compressed, as raw deflate or a zstd frame.
+  Other messages are never compressed.
+
+A 3×1 grid with a mover facing up in its last cell, two empty cells and then one run of one mover:
+
+```
+{}
+```
+
+A batch setting a push cell facing down at 1, 2 and deleting it again:
+
+```
+{}
+```
+
+## JSON
+
+Offered as `json`. Messages are sent in text frames as an object with the variant name as `type` and its fields
+as `data`, a list for tuple variants, left out for `GetGrid`. Cells of a grid are `null` when empty and
+`[id, direction]` otherwise. Batches are allowed as in version 2 and the same limits as for binary messages apply.
+
+```
+{}
+{}
+```
+", hex(&set_grid), hex(&batch), json(&set_grid), json(&batch))
+    }
+
+    /// Compares a generated document with the checked in one, or updates it with `UPDATE_SCHEMA=1`.
+    fn check_document(name: &str, generated: String) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("docs").join(name);
+        if env::var_os("UPDATE_SCHEMA").is_some() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, generated).unwrap();
+            return;
+        }
+        let current = fs::read_to_string(&path).unwrap_or_default();
+        assert!(current == generated, "{} is out of date, run the tests with UPDATE_SCHEMA=1", path.display());
+    }
+
+    #[test]
+    fn documents_are_up_to_date() {
+        let schema = Schema::of::<JMMessage>();
+        check_document("protocol.md", schema.to_markdown(TITLE, INTRO) + &other_versions());
+        check_document("protocol.json", serde_json::to_string_pretty(&schema).unwrap() + "\n");
+    }
+
+    #[test]
+    fn recursive_types_are_referenced() {
+        let schema = Schema::of::<JMMessage>();
+        assert_eq!(schema.root, Type::Ref { name: "JMMessage" });
+        let names = schema.definitions.iter().map(Definition::name).collect::<Vec<_>>();
+        assert_eq!(names, ["JMMessage", "Grid", "Cell"]);
+    }
+}
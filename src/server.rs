@@ -1,19 +1,27 @@
-use std::{net::SocketAddr, sync::{Arc, Mutex}, collections::HashMap};
+use std::{fmt, net::SocketAddr, sync::{Arc, Mutex}, collections::HashMap, time::{Duration, Instant, SystemTime}};
 
-use async_channel::Sender;
 use futures::{future, StreamExt, pin_mut, SinkExt, TryStreamExt};
 use futures_channel::mpsc::{unbounded, UnboundedSender};
-use tokio::net::TcpStream;
-use tokio_tungstenite::{tungstenite::{Message, Error, http::HeaderValue, handshake::server::{Request, Response}}, WebSocketStream, accept_hdr_async};
+use subtle::ConstantTimeEq;
+use tokio::{io::{AsyncRead, AsyncWrite}, net::TcpStream};
+use tokio_tungstenite::{tungstenite::{Message, Error, http::{HeaderValue, StatusCode}, handshake::server::{Callback, Request, Response, ErrorResponse}, protocol::{CloseFrame, WebSocketConfig, frame::coding::CloseCode}}, WebSocketStream, accept_hdr_async, accept_hdr_async_with_config};
 
-use crate::{binary_io::{OutputStream, InputStream}, messages::JMMessage, GRID};
+use jell_machine_server::{binary_io::{OutputStream, InputStream, DecodeError, DecodeLimits}, messages::JMMessage, protocol::{Protocol, Version}};
+
+use crate::{GRID, log::{LogRecord, Level, Category, Logger}, limits::{ConnectionTracker, Rejection}, tls::Tls, config::SharedConfig, shutdown::Shutdown, outbox::Outbox, audit::{Audit, AuditEntry}, time::timestamp, playback::{Playback, Recorder}};
 
 macro_rules! log {
-    [$to:ident: $format:literal] => {
-        log!($to: $format,);
+    [$to:ident, $level:ident, $category:ident($client:expr)[$emphasis:expr]: $($format:tt)*] => {
+        $to.log(LogRecord::new(Level::$level, Category::$category, Some($client.to_string()), format!($($format)*)).emphasize($emphasis))
+    };
+    [$to:ident, $level:ident, $category:ident[$emphasis:expr]: $($format:tt)*] => {
+        $to.log(LogRecord::new(Level::$level, Category::$category, None, format!($($format)*)).emphasize($emphasis))
     };
-    [$to:ident: $format:literal, $($arg:tt)*] => {
-        let _ = $to.send(format!($format, $($arg)*)).await.unwrap();
+    [$to:ident, $level:ident, $category:ident($client:expr): $($format:tt)*] => {
+        $to.log(LogRecord::new(Level::$level, Category::$category, Some($client.to_string()), format!($($format)*)))
+    };
+    [$to:ident, $level:ident, $category:ident: $($format:tt)*] => {
+        $to.log(LogRecord::new(Level::$level, Category::$category, None, format!($($format)*)))
     };
 }
 
@@ -40,11 +48,7 @@ macro_rules! ok {
 
 macro_rules! send {
     ($sender:expr, $msg:expr) => {
-        let _ = $sender.1.unbounded_send(Message::Binary({
-            let mut stream = OutputStream::new();
-            $msg.write_v1(&mut stream);
-            stream.bytes
-        }));
+        let _ = $sender.sender.unbounded_send(encode(&$msg, $sender.protocol));
     };
 }
 
@@ -57,40 +61,104 @@ macro_rules! respond {
     };
 }
 
-pub async fn handle_connection(stream: TcpStream, addr: SocketAddr, state: State) {
-    let (stream, ref version) = read_sec_header(stream).await;
+pub async fn handle_tls_connection(stream: TcpStream, addr: SocketAddr, tls: Tls, state: State) {
+    let log = state.log.clone();
 
+    match tls.acceptor().accept(stream).await {
+        Ok(stream) => handle_connection(stream, addr, state).await,
+        Err(e) => {
+            log!(log, Warn, Client: "TLS handshake with {} failed: {}", addr, e);
+        }
+    }
+}
+
+pub async fn handle_connection<S>(stream: S, addr: SocketAddr, state: State) where S: AsyncRead + AsyncWrite + Unpin {
     let log = state.log.clone();
 
-    ok!(stream.ok() => stream else log!(log: "\x1b[32m[CLIENT] Connection from {} failed.\x1b[m", addr));
-    ok!(version else log!(log: "\x1b[32m[CLIENT] Connection from {} failed: did not specify client version.\x1b[m", addr));
+    let _slot = match state.connections.acquire(addr.ip()) {
+        Ok(slot) => slot,
+        Err(rejection) => {
+            // no slot is taken, but the socket is held until the handshake ends
+            let _ = tokio::time::timeout(HANDSHAKE_TIMEOUT, reject_connection(stream, rejection)).await;
+            log!(log, Warn, Client: "Rejected connection from {}: {}.", addr, rejection);
+            return;
+        }
+    };
+
+    let config = state.config.get();
+    let limits = config.decode_limits();
+    let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_sec_header(stream, config.server.password.as_deref(), &limits)).await;
+    ok!(handshake.ok() => handshake else log!(log, Warn, Client: "Connection from {} failed: the handshake timed out.", addr));
+    let (stream, protocol, nickname) = handshake;
+
+    if let Err(Error::Http(res)) = &stream {
+        if res.status() == StatusCode::UNAUTHORIZED {
+            log!(log, Warn, Client: "Rejected connection from {}: wrong password.", addr);
+            return;
+        }
+        if res.status() == StatusCode::BAD_REQUEST {
+            log!(log, Warn, Client: "Rejected connection from {}: no supported protocol version.", addr);
+            return;
+        }
+    }
+    ok!(stream.ok() => stream else log!(log, Warn, Client: "Connection from {} failed.", addr));
+    ok!(protocol else log!(log, Warn, Client: "Connection from {} failed: did not specify client version.", addr));
 
     let client_id = rand::random::<u64>();
     let client_id = format!("{:x}", client_id);
-    log!(log: "\x1b[32m[CLIENT:{}] New connection from {} with version {}.\x1b[m", client_id, addr, version);
-
     let (tx, rx) = unbounded();
-    state.clients.lock().unwrap().insert(addr, (client_id.clone(), tx));
+    let nickname = {
+        let mut clients = state.clients.lock().unwrap();
+        let nickname = nickname.map(|nickname| unique_nickname(nickname, &clients));
+        clients.insert(addr, Client {
+            id: client_id.clone(),
+            nickname: nickname.clone(),
+            sender: tx,
+            protocol,
+            outbox: Outbox::default(),
+            connected: Instant::now(),
+            edits: 0,
+        });
+        nickname
+    };
+    match &nickname {
+        Some(nickname) => { log!(log, Info, Client(client_id)[nickname]: "New connection from {} as {} with version {}.", addr, nickname, protocol); }
+        None => { log!(log, Info, Client(client_id): "New connection from {} with version {}.", addr, protocol); }
+    }
 
     let (mut out, inp) = stream.split();
 
-    let _ = out.send(Message::Binary({
-        let mut stream = OutputStream::new();
-        JMMessage::SetGrid(GRID.lock().unwrap().clone()).write_v1(&mut stream);
-        stream.bytes
-    })).await;
+    let grid = encode(&JMMessage::SetGrid(GRID.lock().unwrap().clone()), protocol);
+    let _ = out.send(grid).await;
+    if let Some(motd) = config.server.motd {
+        let _ = out.send(notice(protocol, "motd", motd)).await;
+    }
 
     let handle_input = inp.try_for_each(|msg| {
-        if let Message::Ping(data) = msg {
-            if let Some(cl) = state.clients.lock().unwrap().get(&addr) {
-                let _ = cl.1.unbounded_send(Message::Pong(data));
+        let state = state.clone();
+        let client = (addr, client_id.clone());
+        async move {
+            let data = match msg {
+                Message::Ping(data) => {
+                    if let Some(cl) = state.clients.lock().unwrap().get(&addr) {
+                        let _ = cl.sender.unbounded_send(Message::Pong(data));
+                    }
+                    return Ok(());
+                },
+                Message::Binary(data) if protocol.version != Version::Json => data,
+                Message::Text(text) if protocol.version == Version::Json => text.into_bytes(),
+                _ => return Ok(()),
+            };
+            if let Err(e) = process_input(InputStream::with_limits(&data, limits), protocol, client.clone(), state.clone()) {
+                let log = &state.log;
+                log!(log, Warn, Client(client.1): "Invalid message: {}", e);
+                if let Some(cl) = state.clients.lock().unwrap().get(&addr) {
+                    let _ = cl.sender.unbounded_send(notice(protocol, "error", e.to_string()));
+                }
             }
-        }
-        else if let Message::Binary(data) = msg {
-            process_input(InputStream::new(data), version, (addr, client_id.clone()), state.clone());
-        }
 
-        future::ok(())
+            Ok(())
+        }
     });
 
     let fut_forward = rx.map(Ok).forward(out);
@@ -98,76 +166,389 @@ pub async fn handle_connection(stream: TcpStream, addr: SocketAddr, state: State
     pin_mut!(fut_forward, handle_input);
     future::select(fut_forward, handle_input).await;
 
-    log!(log: "\x1b[31m[CLIENT:{}] {} disconnected\x1b[m", client_id, addr);
+    log!(log, Info, Client(client_id): "{} disconnected", addr);
     state.clients.lock().unwrap().remove(&addr);
 }
 
-async fn read_sec_header(stream: TcpStream) -> (Result<WebSocketStream<TcpStream>, Error>, Option<String>) {
-    let mut sec_websocket_protocol = None;
-    let stream = accept_hdr_async(stream, |req: &Request, mut res: Response| {
-		sec_websocket_protocol = req.headers().get("Sec-WebSocket-Protocol").and_then(|s| s.to_str().map(|v| v.to_string()).ok());
-        if let Some(protocol) = &sec_websocket_protocol {
-            res.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_str(protocol).unwrap());
+/// Answers the handshake with the status of the rejection.
+impl Callback for Rejection {
+    fn on_request(self, _: &Request, _: Response) -> Result<Response, ErrorResponse> {
+        let mut res = ErrorResponse::new(Some(format!("{}", self)));
+        *res.status_mut() = self.status();
+        Err(res)
+    }
+}
+
+async fn reject_connection<S>(stream: S, rejection: Rejection) where S: AsyncRead + AsyncWrite + Unpin {
+    let _ = accept_hdr_async(stream, rejection).await;
+}
+
+#[allow(clippy::result_large_err)]
+async fn read_sec_header<S>(stream: S, password: Option<&str>, limits: &DecodeLimits) -> (Result<WebSocketStream<S>, Error>, Option<Protocol>, Option<String>) where S: AsyncRead + AsyncWrite + Unpin {
+    let mut protocol = None;
+    let mut nickname = None;
+    let config = WebSocketConfig {
+        max_message_size: Some(limits.max_message_size),
+        max_frame_size: Some(limits.max_message_size),
+        ..WebSocketConfig::default()
+    };
+    let stream = accept_hdr_async_with_config(stream, |req: &Request, mut res: Response| {
+        if let Some(password) = password {
+            if !password_matches(query_param(req, "password").as_deref(), password) {
+                let mut res = ErrorResponse::new(Some("wrong password".to_string()));
+                *res.status_mut() = StatusCode::UNAUTHORIZED;
+                return Err(res);
+            }
+        }
+        if let Some(offered) = req.headers().get("Sec-WebSocket-Protocol").and_then(|s| s.to_str().ok()) {
+            let Some(negotiated) = Protocol::negotiate(offered) else {
+                let mut res = ErrorResponse::new(Some("unsupported protocol version".to_string()));
+                *res.status_mut() = StatusCode::BAD_REQUEST;
+                return Err(res);
+            };
+            res.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_str(&negotiated.to_string()).unwrap());
+            protocol = Some(negotiated);
         }
+        nickname = query_param(req, "nickname").and_then(|name| clean_nickname(&name));
         Ok(res)
-	}).await;
+	}, Some(config)).await;
+
+    (stream, protocol, nickname)
+}
+
+/// Nicknames are shown in the console and used in commands, so they are kept
+/// short and whitespace and control characters are replaced.
+fn clean_nickname(name: &str) -> Option<String> {
+    let name: String = name.trim().chars()
+        .take(MAX_NICKNAME_LENGTH)
+        .map(|ch| if ch.is_whitespace() || ch.is_control() { '_' } else { ch })
+        .collect();
+    (!name.is_empty()).then_some(name)
+}
+
+/// Console commands take ids and nicknames alike, so a nickname that another
+/// client goes by or that could be a client id gets a `_2`, `_3`, ... suffix.
+fn unique_nickname(name: String, clients: &HashMap<SocketAddr, Client>) -> String {
+    let taken = |name: &str| could_be_id(name) || find_client(clients, name).is_some();
+    if !taken(&name) {
+        return name;
+    }
+    (2..).map(|n| {
+        let suffix = format!("_{}", n);
+        let base: String = name.chars().take(MAX_NICKNAME_LENGTH - suffix.len()).collect();
+        base + &suffix
+    }).find(|name| !taken(name)).unwrap()
+}
+
+/// Client ids are random 64 bit numbers in lowercase hex.
+fn could_be_id(name: &str) -> bool {
+    name.len() <= 16 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
 
-    (stream, sec_websocket_protocol)
+/// Compares in constant time so the password can't be guessed byte by byte
+/// from how long rejecting takes, only its length can.
+fn password_matches(given: Option<&str>, password: &str) -> bool {
+    given.is_some_and(|given| bool::from(given.as_bytes().ct_eq(password.as_bytes())))
 }
 
-fn process_input(stream: InputStream, version: &str, addr: (SocketAddr, String), state: State) -> Option<()> {
-    match version {
-        "1" => process_v1(stream, addr, state),
-        _ => { None },
+fn query_param(req: &Request, name: &str) -> Option<String> {
+    req.uri().query()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+fn percent_decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => match rest.get(..2).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()) {
+                Some(decoded) => {
+                    bytes.push(decoded);
+                    rest = &rest[2..];
+                }
+                None => bytes.push(byte),
+            },
+            _ => bytes.push(byte),
+        }
     }
+    String::from_utf8_lossy(&bytes).into_owned()
 }
 
-fn process_v1(mut stream: InputStream, client: (SocketAddr, String), state: State) -> Option<()> {
-    let msg = JMMessage::parse_v1(&mut stream)?;
+enum InputError {
+    Decode(DecodeError),
+    OutOfBounds(u16, u16),
+    Unexpected(&'static str),
+    Playback,
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputError::Decode(e) => write!(f, "{}", e),
+            InputError::OutOfBounds(x, y) => write!(f, "cell {} {} is outside of the grid", x, y),
+            InputError::Unexpected(name) => write!(f, "unexpected {} message", name),
+            InputError::Playback => write!(f, "the server is playing a replay, edits are not accepted"),
+        }
+    }
+}
+
+fn process_input(mut stream: InputStream, protocol: Protocol, client: (SocketAddr, String), state: State) -> Result<(), InputError> {
+    let msg = JMMessage::parse(&mut stream, protocol).map_err(InputError::Decode)?;
+    process_message(msg, &client, &state)
+}
 
+fn process_message(msg: JMMessage, client: &(SocketAddr, String), state: &State) -> Result<(), InputError> {
     match msg {
         JMMessage::GetGrid => {
             respond!(state, client, JMMessage::SetGrid(GRID.lock().unwrap().clone()));
         },
         JMMessage::SetGrid(_) => {},
+        JMMessage::Delete(..) => { return Err(InputError::Unexpected("Delete")); },
         JMMessage::SetCell(x, y, cell_id, direction) => {
-            let mut grid = GRID.lock().unwrap();
-            if x >= grid.width || y >= grid.height {
-                return None;
+            if state.playback.is_some() {
+                return Err(InputError::Playback);
             }
-            if cell_id.is_empty() {
-                *grid.get(x, y) = None;
+            if !set_cell(state, x, y, cell_id, direction, Some(client)) {
+                return Err(InputError::OutOfBounds(x, y));
             }
-            else {
-                *grid.get(x, y) = Some((cell_id.clone(), direction));
-            }
-            drop(grid);
-
-            let clients = state.clients.lock().unwrap();
-            for (cur_addr, tx) in clients.iter() {
-                if cur_addr == &client.0 { continue; }
-                send!(tx, JMMessage::SetCell(x, y, cell_id.clone(), direction));
+        },
+        JMMessage::Batch(messages) => {
+            for msg in messages {
+                process_message(msg, client, state)?;
             }
         },
-        _ => { return None; },
     }
 
-    Some(())
+    Ok(())
+}
+
+/// Changes a cell and passes the change on to every client but `editor`, who
+/// made it. Returns `false` when the cell is outside of the grid.
+pub fn set_cell(state: &State, x: u16, y: u16, cell_id: String, direction: u8, editor: Option<&(SocketAddr, String)>) -> bool {
+    let mut grid = GRID.lock().unwrap();
+    if x >= grid.width || y >= grid.height {
+        return false;
+    }
+    let new = match cell_id.is_empty() {
+        true => None,
+        false => Some((cell_id.clone(), direction)),
+    };
+    let old = std::mem::replace(grid.get(x, y), new.clone());
+    drop(grid);
+
+    if let Some(recorder) = &state.recorder {
+        recorder.record(JMMessage::SetCell(x, y, cell_id.clone(), direction));
+    }
+
+    let mut clients = state.clients.lock().unwrap();
+    for (cur_addr, cl) in clients.iter_mut() {
+        if editor.is_none_or(|editor| &editor.0 != cur_addr) {
+            cl.outbox.push(x, y, cell_id.clone(), direction);
+        }
+    }
+    let Some(editor) = editor else { return true };
+    // an editor that disconnected meanwhile is still audited, without its nickname
+    let nickname = match clients.get_mut(&editor.0).filter(|cl| cl.id == editor.1) {
+        Some(cl) => {
+            cl.edits += 1;
+            cl.nickname.clone()
+        }
+        None => None,
+    };
+    if let Some(audit) = &state.audit {
+        audit.record(AuditEntry {
+            time: timestamp(SystemTime::now()),
+            client: editor.1.clone(),
+            nickname,
+            addr: editor.0,
+            x,
+            y,
+            old,
+            new,
+        });
+    }
+    true
+}
+
+/// Encodes a message the way the client's protocol wants it, JSON goes into text frames.
+pub fn encode(message: &JMMessage, protocol: Protocol) -> Message {
+    let mut stream = OutputStream::new();
+    message.write(&mut stream, protocol);
+    match protocol.version {
+        Version::Json => Message::Text(String::from_utf8(stream.bytes).unwrap()),
+        _ => Message::Binary(stream.bytes),
+    }
 }
 
-type Client = (String, UnboundedSender<Message>);
+/// A text message from the server, like the motd or an error. JSON clients
+/// get it as `{"<kind>": text}`, everyone else as plain text.
+fn notice(protocol: Protocol, kind: &str, text: String) -> Message {
+    match protocol.version {
+        Version::Json => Message::Text(serde_json::json!({ kind: text }).to_string()),
+        _ if kind == "error" => Message::Text(format!("error: {}", text)),
+        _ => Message::Text(text),
+    }
+}
+
+/// Sends everything the clients' outboxes collected since the last tick.
+pub fn flush_outboxes(state: &State) {
+    let mut clients = state.clients.lock().unwrap();
+    for cl in clients.values_mut() {
+        if cl.outbox.is_empty() { continue; }
+        for frame in cl.outbox.flush(cl.protocol) {
+            let _ = cl.sender.unbounded_send(frame);
+        }
+    }
+}
+
+pub async fn run_ticks(state: State, tick_rate: u32) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1) / tick_rate);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => flush_outboxes(&state),
+            _ = state.shutdown.wait() => break,
+        }
+    }
+}
+
+/// Sends a close frame to every client and ends their connections once everything queued has been sent.
+pub fn close_all(state: &State, reason: &str) {
+    flush_outboxes(state);
+    let clients = state.clients.lock().unwrap();
+    for cl in clients.values() {
+        let _ = cl.sender.unbounded_send(Message::Close(Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: reason.to_string().into(),
+        })));
+        cl.sender.close_channel();
+    }
+}
+
+const MAX_NICKNAME_LENGTH: usize = 24;
+
+/// How long a client has to finish the WebSocket handshake, it holds a
+/// connection slot meanwhile.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Client {
+    pub id: String,
+    /// Given as `?nickname=` in the connection url.
+    pub nickname: Option<String>,
+    pub sender: UnboundedSender<Message>,
+    pub protocol: Protocol,
+    pub outbox: Outbox,
+    pub connected: Instant,
+    /// Cells this client has set.
+    pub edits: u64,
+}
+
+pub type Clients = Arc<Mutex<HashMap<SocketAddr, Client>>>;
+
+/// The client called `name` in console commands, ids win over nicknames.
+pub fn find_client<'a>(clients: &'a HashMap<SocketAddr, Client>, name: &str) -> Option<&'a Client> {
+    clients.values().find(|cl| cl.id == name)
+        .or_else(|| clients.values().find(|cl| cl.nickname.as_deref() == Some(name)))
+}
+
+/// What the console shows about a connected client.
+pub struct ClientInfo {
+    pub id: String,
+    pub nickname: Option<String>,
+    pub addr: SocketAddr,
+    pub protocol: Protocol,
+    pub connected: Instant,
+    pub edits: u64,
+}
+
+/// The connected clients, the longest connected first.
+pub fn client_list(clients: &Clients) -> Vec<ClientInfo> {
+    let mut list: Vec<_> = clients.lock().unwrap().iter().map(|(addr, cl)| ClientInfo {
+        id: cl.id.clone(),
+        nickname: cl.nickname.clone(),
+        addr: *addr,
+        protocol: cl.protocol,
+        connected: cl.connected,
+        edits: cl.edits,
+    }).collect();
+    list.sort_by_key(|info| info.connected);
+    list
+}
 
 #[derive(Clone)]
 pub struct State {
-    pub clients: Arc<Mutex<HashMap<SocketAddr, Client>>>,
-    pub log: Sender<String>
+    pub clients: Clients,
+    pub config: SharedConfig,
+    pub connections: ConnectionTracker,
+    pub shutdown: Shutdown,
+    pub log: Logger,
+    /// Where grid changes are recorded, if configured.
+    pub audit: Option<Audit>,
+    /// Records a replay with `--record`.
+    pub recorder: Option<Recorder>,
+    /// Set while playing a replay with `--play`, clients can't edit then.
+    pub playback: Option<Playback>,
 }
 
 impl State {
-    pub fn new(log: Sender<String>) -> Self {
+    pub fn new(log: Logger, clients: Clients, config: SharedConfig, connections: ConnectionTracker, audit: Option<Audit>, recorder: Option<Recorder>, playback: Option<Playback>) -> Self {
         Self {
-            clients: Arc::new(Mutex::new(HashMap::new())),
-            log
+            clients,
+            config,
+            connections,
+            shutdown: Shutdown::new(),
+            log,
+            audit,
+            recorder,
+            playback,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clients(names: &[(&str, Option<&str>)]) -> HashMap<SocketAddr, Client> {
+        names.iter().enumerate().map(|(i, (id, nickname))| {
+            (SocketAddr::from(([127, 0, 0, 1], i as u16)), Client {
+                id: id.to_string(),
+                nickname: nickname.map(str::to_string),
+                sender: unbounded().0,
+                protocol: Protocol::V1,
+                outbox: Outbox::default(),
+                connected: Instant::now(),
+                edits: 0,
+            })
+        }).collect()
+    }
+
+    #[test]
+    fn nicknames_are_unique() {
+        let clients = clients(&[("3fa2", Some("alice")), ("b7", Some("bob_2")), ("c0ffee", Some("bob"))]);
+        assert_eq!(unique_nickname("carol".to_string(), &clients), "carol");
+        assert_eq!(unique_nickname("alice".to_string(), &clients), "alice_2");
+        assert_eq!(unique_nickname("bob".to_string(), &clients), "bob_3");
+        // ids are taken, connected or not
+        assert_eq!(unique_nickname("c0ffee".to_string(), &clients), "c0ffee_2");
+        assert_eq!(unique_nickname("beef".to_string(), &clients), "beef_2");
+        assert_eq!(unique_nickname("Beef".to_string(), &clients), "Beef");
+
+        let long = "x".repeat(MAX_NICKNAME_LENGTH);
+        let clients = self::clients(&[("1", Some(&long))]);
+        assert_eq!(unique_nickname(long.clone(), &clients), format!("{}_2", &long[2..]));
+    }
+
+    #[test]
+    fn ids_win_over_nicknames() {
+        let clients = clients(&[("3fa2", Some("b7")), ("b7", None)]);
+        assert_eq!(find_client(&clients, "b7").unwrap().id, "b7");
+        assert_eq!(find_client(&clients, "3fa2").unwrap().id, "3fa2");
+        assert!(find_client(&clients, "alice").is_none());
+    }
+}
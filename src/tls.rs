@@ -0,0 +1,149 @@
+use std::{fs::File, io::{self, BufReader}, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use tokio_rustls::{TlsAcceptor, rustls::ServerConfig};
+
+/// TLS termination for incoming connections. The certificate can be reloaded
+/// at runtime, connections that are already open keep their old certificate.
+#[derive(Clone)]
+pub struct Tls {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    acceptor: Arc<Mutex<TlsAcceptor>>,
+}
+
+impl Tls {
+    pub fn load(cert_path: PathBuf, key_path: PathBuf) -> io::Result<Self> {
+        let config = load_server_config(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            acceptor: Arc::new(Mutex::new(TlsAcceptor::from(config))),
+        })
+    }
+
+    pub fn reload(&self) -> io::Result<()> {
+        let config = load_server_config(&self.cert_path, &self.key_path)?;
+        *self.acceptor.lock().unwrap() = TlsAcceptor::from(config);
+        Ok(())
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.lock().unwrap().clone()
+    }
+}
+
+fn load_server_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("no certificates found in {}", cert_path.display())));
+    }
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("no private key found in {}", key_path.display())))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use rcgen::{generate_simple_self_signed, CertifiedKey};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+    use tokio_rustls::{TlsConnector, rustls::{ClientConfig, RootCertStore, pki_types::{CertificateDer, ServerName}}};
+
+    use super::*;
+
+    struct TempCert {
+        dir: PathBuf,
+        cert: CertificateDer<'static>,
+    }
+
+    impl TempCert {
+        fn generate(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("jell-machine-tls-{}-{:x}", name, rand::random::<u64>()));
+            fs::create_dir_all(&dir).unwrap();
+            let mut cert = Self { dir, cert: CertificateDer::from(Vec::new()) };
+            cert.regenerate();
+            cert
+        }
+
+        fn regenerate(&mut self) {
+            let CertifiedKey { cert, key_pair } = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+            fs::write(self.cert_path(), cert.pem()).unwrap();
+            fs::write(self.key_path(), key_pair.serialize_pem()).unwrap();
+            self.cert = cert.der().clone();
+        }
+
+        fn cert_path(&self) -> PathBuf { self.dir.join("cert.pem") }
+        fn key_path(&self) -> PathBuf { self.dir.join("key.pem") }
+    }
+
+    impl Drop for TempCert {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    async fn echo_once(tls: &Tls, trusted: &CertificateDer<'static>) -> io::Result<Vec<u8>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let acceptor = tls.acceptor();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = acceptor.accept(stream).await?;
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(&buf).await?;
+            stream.shutdown().await
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted.clone()).unwrap();
+        let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector.connect(ServerName::try_from("localhost").unwrap(), stream).await?;
+        stream.write_all(b"hello").await?;
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await?;
+        let _ = server.await;
+        Ok(reply)
+    }
+
+    #[tokio::test]
+    async fn handshake_with_self_signed_cert() {
+        let cert = TempCert::generate("handshake");
+        let tls = Tls::load(cert.cert_path(), cert.key_path()).unwrap();
+
+        assert_eq!(echo_once(&tls, &cert.cert).await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn reload_picks_up_new_cert() {
+        let mut cert = TempCert::generate("reload");
+        let tls = Tls::load(cert.cert_path(), cert.key_path()).unwrap();
+        let old = cert.cert.clone();
+
+        cert.regenerate();
+        tls.reload().unwrap();
+
+        assert_eq!(echo_once(&tls, &cert.cert).await.unwrap(), b"hello");
+        assert!(echo_once(&tls, &old).await.is_err());
+    }
+
+    #[test]
+    fn missing_key_is_an_error() {
+        let cert = TempCert::generate("missing-key");
+        fs::write(cert.key_path(), "").unwrap();
+
+        assert!(Tls::load(cert.cert_path(), cert.key_path()).is_err());
+    }
+}
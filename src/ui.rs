@@ -1,214 +1,580 @@
-use std::{io::{stdout, Stdout}, process};
+use std::{collections::VecDeque, io::{stdout, Stdout}, thread, time::Duration};
 
-use ansi_cut::AnsiCut;
 use async_channel::{Sender, Receiver};
-use crossterm::{terminal::{enable_raw_mode, EnterAlternateScreen, disable_raw_mode, Clear, ClearType::CurrentLine, self, LeaveAlternateScreen}, execute, event::{EventStream, Event, KeyCode, KeyModifiers}, cursor, style::Print};
-use futures::{StreamExt, FutureExt, stream::select, future};
+use crossterm::{terminal::{enable_raw_mode, EnterAlternateScreen, disable_raw_mode, Clear, ClearType::CurrentLine, self, LeaveAlternateScreen}, execute, event::{EventStream, Event, KeyCode, KeyModifiers, EnableMouseCapture, DisableMouseCapture, MouseEventKind, MouseButton}, cursor, style::Print};
+use futures::{StreamExt, FutureExt, stream::{self, select}, future};
+use tokio::runtime::Builder;
 
-use crate::{log::format_log, chat::ChatMessage};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-pub fn create_ui() -> (Sender<String>, Receiver<ChatMessage>) {
-    let (ls, lr) = async_channel::bounded(10);
+use crate::{log::{LogRecord, Level, Category, Filter, LOG_CAPACITY, highlight_matches, strip_ansi, cut_visible}, chat::{ChatMessage, COMMANDS}, config::LogConfig, history::History, line_edit::LineEditor, server::ClientInfo, grid_view::GridView, GRID};
+
+/// Columns of the client panel, including its border.
+const PANEL_WIDTH: usize = 36;
+
+/// Starts the console, `clients` gives the connected clients for the client
+/// panel and tab completion.
+pub fn create_ui(config: &LogConfig, clients: impl Fn() -> Vec<ClientInfo> + Send + 'static) -> (Sender<LogRecord>, Receiver<ChatMessage>) {
+    let (ls, lr) = async_channel::bounded(LOG_CAPACITY);
     let (is, ir) = async_channel::bounded(20);
     let (cs, cr) = async_channel::unbounded();
 
-    // drawing
-    tokio::spawn(async move {
+    let scrollback = config.scrollback;
+    let history = History::load(config.history.clone());
+
+    // drawing, on a thread of its own because writing to the terminal blocks
+    // while it is busy or suspended
+    thread::spawn(move || Builder::new_current_thread().enable_time().build().unwrap().block_on(async move {
         enable_raw_mode().unwrap();
-        let mut screen = Screen::new();
+        let (history, error) = match history {
+            Ok(history) => (history, None),
+            Err(e) => (History::default(), Some(e)),
+        };
+        let mut screen = Screen::new(scrollback, history, Box::new(clients));
+        if let Some(e) = error {
+            screen.log(LogRecord::new(Level::Error, Category::Server, None, format!("Error loading console history: {}", e)));
+        }
         screen.draw_log_window();
         screen.draw_input_bar();
 
+        // the client panel shows connection times, so it is redrawn every second
+        let ticks = stream::unfold((), |()| async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Some((ConsoleEvent::Tick, ()))
+        });
+
         select(
-            lr.map(ConsoleEvent::Log),
-            ir.map(ConsoleEvent::UserEvent)
+            // records that arrive together are drawn once
+            select(lr.ready_chunks(LOG_CAPACITY).map(ConsoleEvent::Log), ir.map(ConsoleEvent::UserEvent)),
+            ticks
         ).for_each(|msg| {
             match msg {
-                ConsoleEvent::Log(msg) => {
-                    screen.log(msg);
+                ConsoleEvent::Log(records) => {
+                    for record in records {
+                        screen.push_log(record);
+                    }
+                    screen.draw_log_window();
+                },
+                ConsoleEvent::Tick => {
+                    match screen.grid_view {
+                        Some(_) => screen.draw_log_window(),
+                        None => screen.draw_client_panel(),
+                    }
                 },
                 ConsoleEvent::UserEvent(event) => {
                     let mut message = None;
                     screen.handle_user_event(event, &mut message);
                     if let Some(message) = message {
-                        cs.try_send(ChatMessage { content: message, sender: "server".into() }).unwrap();
+                        let _ = cs.try_send(ChatMessage { content: message, sender: "server".into() });
                     }
                 },
             }
             future::ready(())
         }).await
-    });
+    }));
 
     // input handling
     tokio::spawn(async move {
         let mut reader = EventStream::new();
         while let Some(event) = reader.next().fuse().await {
-            is.send(event.unwrap()).await.unwrap();
+            let Ok(event) = event else { break };
+            if is.send(event).await.is_err() {
+                break;
+            }
         }
     });
 
     (ls, cr)
 }
 
+/// Leaves the alternate screen, call this before the process exits.
+pub fn restore_terminal() {
+    let _ = execute!(stdout(), DisableMouseCapture, LeaveAlternateScreen);
+    let _ = disable_raw_mode();
+}
+
 enum ConsoleEvent {
-    Log(String),
+    Log(Vec<LogRecord>),
     UserEvent(Event),
+    Tick,
 }
 
 
 struct Screen {
-    logs: Vec<String>,
+    logs: Scrollback,
+    search: Option<Search>,
 
-    current_input: String,
-    position: usize,
-    input_offset: usize,
+    history: History,
+    history_search: Option<Search>,
+    clients: Box<dyn Fn() -> Vec<ClientInfo> + Send>,
+    show_clients: bool,
+    /// Shown instead of the log while it is open.
+    grid_view: Option<GridView>,
+
+    input: LineEditor,
 
     window_size: (u16, u16),
     stdout: Stdout,
 }
 
 impl Screen {
-    fn new() -> Self {
+    fn new(scrollback: usize, history: History, clients: Box<dyn Fn() -> Vec<ClientInfo> + Send>) -> Self {
         let mut stdout = stdout();
         execute!(stdout, EnterAlternateScreen).unwrap();
 
         Self {
-            logs: vec![],
+            logs: Scrollback::new(scrollback),
+            search: None,
+
+            history,
+            history_search: None,
+            clients,
+            show_clients: false,
+            grid_view: None,
 
-            current_input: String::new(),
-            position: 0,
-            input_offset: 0,
+            input: LineEditor::default(),
 
             window_size: terminal::size().unwrap(),
             stdout,
         }
     }
 
-    fn log(&mut self, msg: String) {
-        self.logs.push(msg);
+    fn log(&mut self, record: LogRecord) {
+        self.push_log(record);
         self.draw_log_window();
     }
 
+    /// Adds a record without drawing it yet.
+    fn push_log(&mut self, record: LogRecord) {
+        if self.logs.push(record) {
+            if let Some(search) = &mut self.search {
+                search.current = search.current.and_then(|i| i.checked_sub(1));
+            }
+        }
+    }
+
+    /// Rows of the log window, two lines are for input.
+    fn log_rows(&self) -> usize {
+        self.window_size.1.saturating_sub(2) as usize
+    }
+
+    /// The client panel is left out when it would take more than half of the window.
+    fn panel_shown(&self) -> bool {
+        self.show_clients && self.window_size.0 as usize >= 2 * PANEL_WIDTH
+    }
+
+    fn log_width(&self) -> usize {
+        match self.panel_shown() {
+            true => self.window_size.0 as usize - PANEL_WIDTH,
+            false => self.window_size.0 as usize,
+        }
+    }
+
+    fn cursor_column(&mut self) -> u16 {
+        let column = match (&self.search, &self.history_search) {
+            (_, Some(search)) => search.query.width() + HISTORY_PROMPT.len(),
+            (Some(search), _) => search.query.width() + 2,
+            _ => self.input.view((self.window_size.0 as usize).saturating_sub(2)).1 + 2,
+        };
+        column.min((self.window_size.0 as usize).saturating_sub(1)) as u16
+    }
+
+    /// Draws the log, or the grid while the grid view is open.
     fn draw_log_window(&mut self) {
+        let column = self.cursor_column();
+        let rows = self.log_rows();
+        let width = self.log_width();
+        let (lines, separator) = match &mut self.grid_view {
+            Some(view) => {
+                let grid = GRID.lock().unwrap();
+                (view.render(&grid, width, rows), format!("── {} ", view.status(&grid)))
+            }
+            None => {
+                let query = self.search.as_ref().map_or("", |search| search.query.as_str());
+                let lines = self.logs.visible(rows).into_iter().map(|line| clip(&highlight_matches(line, query), width)).collect();
+                let mut status = Vec::new();
+                if self.logs.offset > 0 {
+                    status.push(format!("{} newer lines below", self.logs.offset));
+                }
+                if self.logs.filter.is_active() {
+                    status.push(format!("showing {}", self.logs.filter));
+                }
+                let separator = match status.is_empty() {
+                    true => String::new(),
+                    false => format!("── {} ", status.join(", ")),
+                };
+                (lines, separator)
+            }
+        };
         let mut stdout = self.stdout.lock();
 
-        let mut y = self.window_size.1 - 3; // two line for input
-        for msg in self.logs.iter().rev() {
-            execute!(
-                stdout,
-                cursor::MoveTo(0, y),
-                Clear(CurrentLine),
-                Print(msg.clone())
-            ).unwrap();
-            y -= 1;
-            if y == 0 {
-                break;
+        // the newest line is at the bottom
+        let empty = rows - lines.len();
+        for row in 0..rows {
+            execute!(stdout, cursor::MoveTo(0, row as u16), Clear(CurrentLine)).unwrap();
+            if row >= empty {
+                execute!(stdout, Print(&lines[row - empty])).unwrap();
             }
         }
 
-        execute!(stdout, cursor::MoveTo((self.position - self.input_offset + 2) as u16, self.window_size.1 - 1)).unwrap();
+        drop(stdout);
+        self.draw_client_panel();
+        let mut stdout = self.stdout.lock();
+
+        let width = self.window_size.0 as usize;
+        let separator = clip(&separator, width);
+        let fill = width.saturating_sub(separator.width());
+        execute!(
+            stdout,
+            cursor::MoveTo(0, self.window_size.1.saturating_sub(2)),
+            Clear(CurrentLine),
+            Print(separator),
+            Print("─".repeat(fill)),
+            cursor::MoveTo(column, self.window_size.1.saturating_sub(1))
+        ).unwrap();
     }
 
-    fn draw_input_bar(&mut self) {
-        let width = self.window_size.0 as usize - 2;
-        let start = self.input_offset;
-        let end = (start + width).min(self.current_input.len());
+    fn draw_client_panel(&mut self) {
+        if !self.panel_shown() {
+            return;
+        }
 
-        let highlighted = input_highlight(&self.current_input[start..end]);
-        let drawn_input = format!("{:width$}", highlighted.cut(start..end), width=width);
+        let clients = (self.clients)();
+        let mut lines = vec![format!("\x1b[1mClients ({})\x1b[0m", clients.len())];
+        for client in &clients {
+            lines.push(String::new());
+            lines.push(format!("\x1b[32m{}\x1b[0m {}", client.id, client.nickname.as_deref().unwrap_or("")));
+            lines.push(format!("{} v{}", client.addr, client.protocol));
+            lines.push(format!("online {}, {} edits", format_duration(client.connected.elapsed()), client.edits));
+        }
+        let rows = self.log_rows();
+        if lines.len() > rows {
+            let hidden = clients.len() - (rows.saturating_sub(2) / 4);
+            lines.truncate(rows.saturating_sub(1));
+            lines.push(format!("\x1b[2mand {} more\x1b[0m", hidden));
+        }
+
+        let column = self.cursor_column();
+        let x = (self.window_size.0 as usize - PANEL_WIDTH) as u16;
+        let inner = PANEL_WIDTH - 2;
+        let mut stdout = self.stdout.lock();
+        for row in 0..rows {
+            let line = clip(lines.get(row).map_or("", String::as_str), inner);
+            let padding = inner - strip_ansi(&line).width();
+            execute!(stdout, cursor::MoveTo(x, row as u16), Print("│ "), Print(line), Print(" ".repeat(padding))).unwrap();
+        }
+        execute!(stdout, cursor::MoveTo(column, self.window_size.1.saturating_sub(1))).unwrap();
+    }
+
+    fn draw_input_bar(&mut self) {
+        let width = (self.window_size.0 as usize).saturating_sub(2);
+        let (prompt, drawn_input) = match (&self.search, &self.history_search) {
+            _ if self.grid_view.is_some() => {
+                ("  ", "\x1b[2marrows move, +/- zoom, click to inspect, Esc closes\x1b[0m".to_string())
+            }
+            (_, Some(search)) => {
+                let found = search.current.map_or("", |i| self.history.get(i));
+                (HISTORY_PROMPT, format!("{}': {}", search.query, found))
+            }
+            (Some(search), _) => {
+                let status = if search.query.is_empty() || search.current.is_some() { "" } else { " \x1b[2m(no match)\x1b[0m" };
+                let skip = search.query.chars().count().saturating_sub(width.saturating_sub(1));
+                ("/ ", format!("{}{}", search.query.chars().skip(skip).collect::<String>(), status))
+            }
+            _ => {
+                // highlighting needs the whole input, the shown part is cut out after
+                let (shown, _) = self.input.view(width);
+                ("> ", cut_visible(&input_highlight(self.input.text()), shown))
+            }
+        };
+        let column = self.cursor_column();
 
         execute!(
             self.stdout,
             cursor::Hide,
-            cursor::MoveTo(0, self.window_size.1 - 2),
-            Clear(CurrentLine),
-            Print("─".repeat(self.window_size.0 as usize)),
-            cursor::MoveTo(0, self.window_size.1 - 1),
+            cursor::MoveTo(0, self.window_size.1.saturating_sub(1)),
             Clear(CurrentLine),
-            Print("> "),
+            Print(prompt),
             Print(drawn_input),
-            cursor::MoveTo((self.position - self.input_offset + 2) as u16, self.window_size.1 - 1),
+            cursor::MoveTo(column, self.window_size.1.saturating_sub(1)),
             cursor::Show
         ).unwrap();
     }
 
+    fn handle_search_key(&mut self, key: KeyCode) {
+        let rows = self.log_rows();
+        let Some(search) = &mut self.search else { return };
+        let newest = self.logs.lines.len();
+        match key {
+            KeyCode::Esc => {
+                self.search = None;
+                return;
+            }
+            KeyCode::Enter | KeyCode::Up => {
+                let before = search.current.unwrap_or(newest);
+                search.current = self.logs.find_older(&search.query, before).or(search.current);
+            }
+            KeyCode::Down => {
+                if let Some(current) = search.current {
+                    search.current = self.logs.find_newer(&search.query, current).or(Some(current));
+                }
+            }
+            KeyCode::Backspace | KeyCode::Char(_) => {
+                match key {
+                    KeyCode::Char(ch) => search.query.push(ch),
+                    _ => { search.query.pop(); }
+                }
+                // stay on the current match while it still matches
+                let before = search.current.map_or(newest, |i| i + 1);
+                search.current = self.logs.find_older(&search.query, before);
+            }
+            _ => {}
+        }
+        if let Some(current) = search.current {
+            self.logs.show(current, rows);
+        }
+    }
+
+    fn handle_history_search_key(&mut self, key: KeyCode, modifiers: KeyModifiers) {
+        let Some(search) = &mut self.history_search else { return };
+        let newest = self.history.len();
+        match key {
+            KeyCode::Char('r') if modifiers.contains(KeyModifiers::CONTROL) => {
+                let before = search.current.unwrap_or(newest);
+                search.current = self.history.find(&search.query, before).or(search.current);
+            }
+            KeyCode::Backspace | KeyCode::Char(_) => {
+                match key {
+                    KeyCode::Char(ch) => search.query.push(ch),
+                    _ => { search.query.pop(); }
+                }
+                let before = search.current.map_or(newest, |i| i + 1);
+                search.current = self.history.find(&search.query, before);
+            }
+            KeyCode::Enter => {
+                if let Some(current) = search.current {
+                    let input = self.history.get(current).to_string();
+                    self.input.set(input);
+                }
+                self.history_search = None;
+            }
+            KeyCode::Esc => {
+                self.history_search = None;
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_view_key(&mut self, key: KeyCode) {
+        let page = self.log_rows() as i32 - 1;
+        let Some(view) = &mut self.grid_view else { return };
+        let grid = GRID.lock().unwrap();
+        match key {
+            KeyCode::Esc | KeyCode::F(3) => {
+                drop(grid);
+                self.close_grid_view();
+            }
+            KeyCode::Left => view.move_cursor(-1, 0, &grid),
+            KeyCode::Right => view.move_cursor(1, 0, &grid),
+            KeyCode::Up => view.move_cursor(0, -1, &grid),
+            KeyCode::Down => view.move_cursor(0, 1, &grid),
+            KeyCode::PageUp => view.move_cursor(0, -page, &grid),
+            KeyCode::PageDown => view.move_cursor(0, page, &grid),
+            KeyCode::Char('+') | KeyCode::Char('=') => view.zoom_in(),
+            KeyCode::Char('-') => view.zoom_out(),
+            _ => {}
+        }
+    }
+
+    /// Does nothing but warn for an empty grid, a loaded one can be 0 by 0.
+    /// The mouse is only captured while the view is open, otherwise the
+    /// terminal keeps it for selecting and copying log lines.
+    fn open_grid_view(&mut self, position: Option<(u16, u16)>) {
+        let grid = GRID.lock().unwrap();
+        if grid.width == 0 || grid.height == 0 {
+            drop(grid);
+            self.log(LogRecord::new(Level::Warn, Category::Command, None, "The grid is empty, there is nothing to view.".to_string()));
+            return;
+        }
+        let (x, y) = position.unwrap_or((grid.width / 2, grid.height / 2));
+        self.grid_view = Some(GridView::new(x.min(grid.width - 1), y.min(grid.height - 1)));
+        execute!(self.stdout, EnableMouseCapture).unwrap();
+    }
+
+    fn close_grid_view(&mut self) {
+        self.grid_view = None;
+        execute!(self.stdout, DisableMouseCapture).unwrap();
+    }
+
+    /// Changes what the log window shows, client nicknames are looked up so
+    /// records about that client are found by id.
+    fn apply_filter(&mut self, args: &[&str]) {
+        let mut args = args.to_vec();
+        let clients = (self.clients)();
+        let id;
+        if let ["client", name] = args[..] {
+            if let Some(client) = clients.iter().find(|client| client.nickname.as_deref() == Some(name)) {
+                id = client.id.clone();
+                args[1] = &id;
+            }
+        }
+
+        let mut filter = self.logs.filter.clone();
+        match filter.apply(&args) {
+            Ok(()) => {
+                self.logs.set_filter(filter);
+                self.log(LogRecord::new(Level::Info, Category::Command, None, format!("Showing {}.", self.logs.filter)));
+            }
+            Err(usage) => self.log(LogRecord::new(Level::Warn, Category::Command, None, usage.to_string())),
+        }
+    }
+
+    fn complete(&mut self) {
+        let names: Vec<_> = (self.clients)().into_iter()
+            .flat_map(|client| std::iter::once(client.id).chain(client.nickname))
+            .collect();
+        let (start, candidates) = completions(self.input.text(), self.input.position(), &names);
+        let completion = match candidates.as_slice() {
+            [] => return,
+            [only] => format!("{} ", only),
+            _ => {
+                let prefix = common_prefix(&candidates);
+                if prefix.len() == self.input.position() - start {
+                    self.log(LogRecord::new(Level::Info, Category::Command, None, format!("Completions: {}", candidates.join(" "))));
+                    return;
+                }
+                prefix.to_string()
+            }
+        };
+        self.input.replace_before(start, &completion);
+    }
+
     fn handle_user_event(&mut self, event: Event, message: &mut Option<String>) {
         match event {
             Event::Key(key) => {
                 let modifiers = key.modifiers;
                 let key = key.code;
+                let rows = self.log_rows();
+                let page = rows.saturating_sub(1).max(1);
                 match key {
                     KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
-                        execute!(self.stdout, LeaveAlternateScreen).unwrap();
-                        disable_raw_mode().unwrap();
-                        process::exit(0);
+                        *message = Some("/stop".to_string());
+                    }
+                    _ if self.history_search.is_some() => {
+                        self.handle_history_search_key(key, modifiers);
                     }
 
-                    KeyCode::Left => {
-                        if self.position > 0 {
-                            self.position -= 1;
-                        }
+                    KeyCode::PageUp => {
+                        self.logs.scroll_up(page, rows);
+                        self.draw_log_window();
                     }
-                    KeyCode::Right => {
-                        if self.position < self.current_input.len() {
-                            self.position += 1;
-                        }
+                    KeyCode::PageDown => {
+                        self.logs.scroll_down(page);
+                        self.draw_log_window();
                     }
-
-                    KeyCode::Backspace => {
-                        if self.position > 0 {
-                            self.current_input.remove(self.position - 1);
-                            self.position -= 1;
-                        }
+                    KeyCode::Char('f') if modifiers.contains(KeyModifiers::CONTROL) => {
+                        self.search.get_or_insert_with(Search::default);
                     }
-                    KeyCode::Delete => {
-                        if self.position < self.current_input.len() {
-                            self.current_input.remove(self.position);
-                        }
+                    KeyCode::F(2) => {
+                        self.show_clients = !self.show_clients;
+                        self.draw_log_window();
                     }
-
-                    KeyCode::Home => {
-                        self.position = 0;
-                        self.input_offset = 0;
+                    _ if self.grid_view.is_some() => {
+                        self.handle_view_key(key);
+                        self.draw_log_window();
                     }
-                    KeyCode::Char('a') if modifiers.contains(KeyModifiers::CONTROL) => {
-                        self.position = 0;
-                        self.input_offset = 0;
+                    KeyCode::F(3) => {
+                        self.open_grid_view(None);
+                        self.draw_log_window();
                     }
-                    KeyCode::End => {
-                        self.position = self.current_input.len();
+                    _ if self.search.is_some() => {
+                        self.handle_search_key(key);
+                        self.draw_log_window();
                     }
-                    KeyCode::Char('e') if modifiers.contains(KeyModifiers::CONTROL) => {
-                        self.position = self.current_input.len();
+                    KeyCode::Esc => {
+                        self.logs.scroll_down(usize::MAX);
+                        self.draw_log_window();
                     }
 
-                    KeyCode::Char('u') if modifiers.contains(KeyModifiers::CONTROL) => {
-                        self.current_input = self.current_input[self.position..].to_string();
-                        self.position = 0;
-                        self.input_offset = 0;
+                    KeyCode::Up => {
+                        if let Some(input) = self.history.older(self.input.text()) {
+                            let input = input.to_string();
+                            self.input.set(input);
+                        }
                     }
-
-                    KeyCode::Enter => {
-                        if self.current_input.starts_with('/') {
-                            self.log(format_log("94", "COMMAND", &self.current_input));
+                    KeyCode::Down => {
+                        if let Some(input) = self.history.newer() {
+                            self.input.set(input);
                         }
-                        *message = Some(self.current_input.clone());
+                    }
+                    KeyCode::Char('r') if modifiers.contains(KeyModifiers::CONTROL) => {
+                        self.history_search = Some(Search::default());
+                    }
+                    KeyCode::Tab => {
+                        self.complete();
+                    }
 
+                    KeyCode::Left if modifiers.contains(KeyModifiers::CONTROL) => self.input.word_left(),
+                    KeyCode::Right if modifiers.contains(KeyModifiers::CONTROL) => self.input.word_right(),
+                    KeyCode::Left => self.input.left(),
+                    KeyCode::Right => self.input.right(),
 
-                        self.current_input.clear();
-                        self.position = 0;
-                        self.input_offset = 0;
-                    }
+                    KeyCode::Backspace => self.input.backspace(),
+                    KeyCode::Delete => self.input.delete(),
+                    KeyCode::Char('w') if modifiers.contains(KeyModifiers::CONTROL) => self.input.delete_word_before(),
+                    KeyCode::Char('d') if modifiers.contains(KeyModifiers::ALT) => self.input.delete_word_after(),
+
+                    KeyCode::Home => self.input.home(),
+                    KeyCode::Char('a') if modifiers.contains(KeyModifiers::CONTROL) => self.input.home(),
+                    KeyCode::End => self.input.end(),
+                    KeyCode::Char('e') if modifiers.contains(KeyModifiers::CONTROL) => self.input.end(),
+
+                    KeyCode::Char('u') if modifiers.contains(KeyModifiers::CONTROL) => self.input.delete_to_start(),
 
-                    KeyCode::Char(ch) => {
-                        self.current_input.insert(self.position, ch);
-                        self.position += 1;
+                    KeyCode::Enter => {
+                        let input = self.input.take();
+                        if input.starts_with('/') {
+                            self.log(LogRecord::new(Level::Info, Category::Command, None, input.clone()));
+                        }
+                        if let Err(e) = self.history.add(&input) {
+                            self.log(LogRecord::new(Level::Error, Category::Server, None, format!("Error saving console history: {}", e)));
+                        }
+                        if let Some(args) = filter_command(&input) {
+                            self.apply_filter(&args);
+                        }
+                        else if let Some(position) = view_command(&input) {
+                            self.open_grid_view(position);
+                            self.draw_log_window();
+                        }
+                        else {
+                            *message = Some(input);
+                        }
                     }
 
+                    KeyCode::Char(ch) => self.input.insert(ch),
+
                     _ => {},
                 }
                 self.draw_input_bar();
             }
+            // only captured while the grid view is open
+            Event::Mouse(mouse) => {
+                let rows = self.log_rows();
+                let width = self.log_width();
+                let Some(view) = &mut self.grid_view else { return };
+                let grid = GRID.lock().unwrap();
+                match mouse.kind {
+                    MouseEventKind::ScrollUp => view.move_cursor(0, -3, &grid),
+                    MouseEventKind::ScrollDown => view.move_cursor(0, 3, &grid),
+                    MouseEventKind::Down(MouseButton::Left) if (mouse.row as usize) < rows && (mouse.column as usize) < width => {
+                        view.click(mouse.column as usize, mouse.row as usize, &grid);
+                    }
+                    _ => return,
+                }
+                drop(grid);
+                self.draw_log_window();
+            }
             Event::Resize(w, h) => {
                 self.window_size = (w, h);
                 self.draw_log_window();
@@ -219,6 +585,192 @@ impl Screen {
     }
 }
 
+const HISTORY_PROMPT: &str = "(reverse-i-search)`";
+
+/// A search prompt, Ctrl-F searches the log and Ctrl-R the history.
+/// `current` is the index of the line or history entry it is on.
+#[derive(Default)]
+struct Search {
+    query: String,
+    current: Option<usize>,
+}
+
+/// Log records for the log window with their lines, oldest first, and how far
+/// the window is scrolled back. Records the filter hides are kept, but they
+/// are not shown, searched or counted for scrolling.
+struct Scrollback {
+    lines: VecDeque<(LogRecord, String)>,
+    capacity: usize,
+    filter: Filter,
+    /// The lines the filter lets through, oldest first. These count every
+    /// line ever pushed, so they stay valid when the oldest lines are dropped.
+    shown: VecDeque<usize>,
+    /// How many lines were dropped, the number of the oldest line.
+    dropped: usize,
+    /// How many of the newest shown lines are below the window.
+    offset: usize,
+}
+
+impl Scrollback {
+    fn new(capacity: usize) -> Self {
+        Self {
+            lines: VecDeque::with_capacity(capacity.min(1024)),
+            capacity,
+            filter: Filter::default(),
+            shown: VecDeque::with_capacity(capacity.min(1024)),
+            dropped: 0,
+            offset: 0,
+        }
+    }
+
+    /// Adds a record and returns whether the oldest one was dropped for it.
+    fn push(&mut self, record: LogRecord) -> bool {
+        let dropped = self.lines.len() == self.capacity;
+        if dropped {
+            self.lines.pop_front();
+            if self.shown.front() == Some(&self.dropped) {
+                self.shown.pop_front();
+            }
+            self.dropped += 1;
+        }
+        let shown = self.filter.matches(&record);
+        if shown {
+            self.shown.push_back(self.dropped + self.lines.len());
+        }
+        let line = record.line();
+        self.lines.push_back((record, line));
+
+        // new lines don't move the window while scrolled back
+        if self.offset > 0 && shown {
+            self.offset = (self.offset + 1).min(self.shown.len() - 1);
+        }
+        dropped
+    }
+
+    fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+        self.shown = (0..self.lines.len()).filter(|&i| self.filter.matches(&self.lines[i].0)).map(|i| self.dropped + i).collect();
+        self.offset = 0;
+    }
+
+    /// The lines of a window with `rows` rows, oldest first.
+    fn visible(&self, rows: usize) -> Vec<&str> {
+        let end = self.shown.len() - self.offset.min(self.shown.len());
+        self.shown.range(end.saturating_sub(rows)..end).map(|&i| self.lines[i - self.dropped].1.as_str()).collect()
+    }
+
+    fn scroll_up(&mut self, amount: usize, rows: usize) {
+        self.offset = self.offset.saturating_add(amount).min(self.shown.len().saturating_sub(rows));
+    }
+
+    fn scroll_down(&mut self, amount: usize) {
+        self.offset = self.offset.saturating_sub(amount);
+    }
+
+    /// Scrolls so line `index` is at the bottom of the window, or as close as possible.
+    fn show(&mut self, index: usize, rows: usize) {
+        let below = self.shown.len() - self.shown.partition_point(|&i| i <= self.dropped + index);
+        self.offset = below.min(self.shown.len().saturating_sub(rows));
+    }
+
+    /// The newest shown line before `before` containing `query`, ignoring case and colors.
+    fn find_older(&self, query: &str, before: usize) -> Option<usize> {
+        (0..before.min(self.lines.len())).rev().find(|&i| self.matches(i, query))
+    }
+
+    /// The oldest shown line after `after` containing `query`.
+    fn find_newer(&self, query: &str, after: usize) -> Option<usize> {
+        (after + 1..self.lines.len()).find(|&i| self.matches(i, query))
+    }
+
+    fn matches(&self, index: usize, query: &str) -> bool {
+        let (record, line) = &self.lines[index];
+        self.filter.matches(record) && contains(line, query)
+    }
+}
+
+fn contains(line: &str, query: &str) -> bool {
+    !query.is_empty() && strip_ansi(line).to_ascii_lowercase().contains(&query.to_ascii_lowercase())
+}
+
+/// The arguments of `/filter`.
+fn filter_command(input: &str) -> Option<Vec<&str>> {
+    let mut parts = input.split_whitespace();
+    match parts.next() {
+        Some("/filter") => Some(parts.collect()),
+        _ => None,
+    }
+}
+
+/// `Some` for `/view` and `/view x y`, with the position to open the grid view at.
+fn view_command(input: &str) -> Option<Option<(u16, u16)>> {
+    let mut parts = input.split_whitespace();
+    if parts.next() != Some("/view") {
+        return None;
+    }
+    match (parts.next(), parts.next(), parts.next()) {
+        (None, ..) => Some(None),
+        (Some(x), Some(y), None) => Some(Some((x.parse().ok()?, y.parse().ok()?))),
+        _ => None,
+    }
+}
+
+/// Cuts a log line to `width` columns.
+fn clip(line: &str, width: usize) -> String {
+    let mut end = 0;
+    let mut used = 0;
+    for grapheme in strip_ansi(line).graphemes(true) {
+        used += grapheme.width();
+        if used > width {
+            break;
+        }
+        end += grapheme.len();
+    }
+    cut_visible(line, 0..end)
+}
+
+/// Rounded to the two largest units, like `5s`, `12m 03s` or `2h 05m`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs / 60 % 60),
+    }
+}
+
+/// Completions for the word before `position` and where that word starts:
+/// command names for the first word of a command, client ids and nicknames
+/// for its arguments.
+fn completions(input: &str, position: usize, client_names: &[String]) -> (usize, Vec<String>) {
+    let start = input[..position].rfind(' ').map_or(0, |i| i + 1);
+    let word = &input[start..position];
+    let candidates = if !input.starts_with('/') {
+        vec![]
+    }
+    else if start == 0 {
+        COMMANDS.iter().map(|(name, _)| format!("/{}", name)).collect()
+    }
+    else {
+        client_names.to_vec()
+    };
+
+    let mut candidates: Vec<_> = candidates.into_iter().filter(|c| c.starts_with(word)).collect();
+    candidates.sort();
+    (start, candidates)
+}
+
+fn common_prefix(candidates: &[String]) -> &str {
+    let first = &candidates[0];
+    let len = candidates[1..].iter().fold(first.len(), |len, candidate| {
+        first[..len].char_indices()
+            .zip(candidate.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(len.min(candidate.len()), |((i, _), _)| i)
+    });
+    &first[..len]
+}
+
 fn input_highlight(input: &str) -> String {
     let input = input.to_string();
     if input.starts_with('/') {
@@ -245,3 +797,143 @@ fn input_highlight(input: &str) -> String {
         input
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scrollback(lines: usize, capacity: usize) -> Scrollback {
+        let mut scrollback = Scrollback::new(capacity);
+        for i in 0..lines {
+            scrollback.push(LogRecord::new(Level::Info, Category::Client, Some((i % 3).to_string()), format!("line {}", i)));
+        }
+        scrollback
+    }
+
+    fn visible(scrollback: &Scrollback, rows: usize) -> Vec<String> {
+        scrollback.visible(rows).into_iter().map(strip_ansi).collect()
+    }
+
+    fn server(message: &str) -> LogRecord {
+        LogRecord::new(Level::Info, Category::Server, None, message.to_string())
+    }
+
+    #[test]
+    fn oldest_lines_are_dropped() {
+        let mut logs = scrollback(5, 3);
+        assert_eq!(visible(&logs, 10), ["[CLIENT:2] line 2", "[CLIENT:0] line 3", "[CLIENT:1] line 4"]);
+        assert!(logs.push(server("new")));
+        assert_eq!(logs.lines.len(), 3);
+
+        // lines the filter shows are dropped with the rest
+        logs.set_filter(Filter { client: Some("1".to_string()), ..Filter::default() });
+        assert_eq!(visible(&logs, 10), ["[CLIENT:1] line 4"]);
+        logs.push(server("newer"));
+        logs.push(LogRecord::new(Level::Info, Category::Client, Some("1".to_string()), "line 5".to_string()));
+        assert_eq!(visible(&logs, 10), ["[CLIENT:1] line 5"]);
+        logs.show(2, 1);
+        assert_eq!(logs.offset, 0);
+    }
+
+    #[test]
+    fn scrolling() {
+        let mut logs = scrollback(10, 100);
+        logs.scroll_up(3, 4);
+        assert_eq!(visible(&logs, 2), ["[CLIENT:2] line 5", "[CLIENT:0] line 6"]);
+
+        // new lines keep the window in place, the top can't be scrolled past
+        logs.push(server("new"));
+        assert_eq!(visible(&logs, 2), ["[CLIENT:2] line 5", "[CLIENT:0] line 6"]);
+        logs.scroll_up(100, 4);
+        assert_eq!(logs.offset, 7);
+        assert_eq!(visible(&logs, 4)[0], "[CLIENT:0] line 0");
+
+        logs.scroll_down(100);
+        assert_eq!(visible(&logs, 1), ["[SERVER] new"]);
+        logs.push(server("newer"));
+        assert_eq!(logs.offset, 0);
+    }
+
+    #[test]
+    fn search() {
+        let mut logs = scrollback(10, 100);
+        assert_eq!(logs.find_older("client:1", 10), Some(7));
+        assert_eq!(logs.find_older("client:1", 7), Some(4));
+        assert_eq!(logs.find_newer("CLIENT:1", 4), Some(7));
+        assert_eq!(logs.find_newer("client:1", 7), None);
+        // escape sequences are not searched
+        assert_eq!(logs.find_older("32m", 10), None);
+        assert_eq!(logs.find_older("", 10), None);
+
+        // lines near the top can't be at the bottom of the window
+        logs.show(1, 4);
+        assert_eq!(visible(&logs, 4)[1], "[CLIENT:1] line 1");
+        logs.show(8, 4);
+        assert_eq!(visible(&logs, 4).last().unwrap(), "[CLIENT:2] line 8");
+    }
+
+    #[test]
+    fn filtering() {
+        let mut logs = scrollback(10, 100);
+        logs.scroll_up(2, 3);
+        logs.set_filter(Filter { client: Some("1".to_string()), ..Filter::default() });
+        assert_eq!(logs.offset, 0);
+        assert_eq!(visible(&logs, 2), ["[CLIENT:1] line 4", "[CLIENT:1] line 7"]);
+
+        // hidden records are kept but don't scroll or match
+        logs.scroll_up(1, 2);
+        logs.push(server("hidden"));
+        assert_eq!(logs.offset, 1);
+        assert_eq!(logs.find_older("line", 10), Some(7));
+        assert_eq!(logs.find_older("line 8", 10), None);
+        logs.show(4, 1);
+        assert_eq!(visible(&logs, 1), ["[CLIENT:1] line 4"]);
+
+        logs.set_filter(Filter::default());
+        assert_eq!(visible(&logs, 1), ["[SERVER] hidden"]);
+        assert_eq!(logs.lines.len(), 11);
+
+        assert_eq!(filter_command("/filter level warn"), Some(vec!["level", "warn"]));
+        assert_eq!(filter_command("/filter"), Some(vec![]));
+        assert_eq!(filter_command("/filters"), None);
+    }
+
+    #[test]
+    fn completion() {
+        let ids = ["3fa2".to_string(), "3f07".to_string(), "b1".to_string()];
+        assert_eq!(completions("/re", 3, &ids), (0, vec!["/reload".to_string()]));
+        assert_eq!(completions("/", 1, &ids).1.len(), COMMANDS.len());
+        assert_eq!(completions("/kick 3f", 8, &ids), (6, vec!["3f07".to_string(), "3fa2".to_string()]));
+        assert_eq!(completions("/tell b hi", 7, &ids), (6, vec!["b1".to_string()]));
+        // chat messages are not completed
+        assert_eq!(completions("re", 2, &ids).1, Vec::<String>::new());
+
+        assert_eq!(common_prefix(&["3f07".to_string(), "3fa2".to_string()]), "3f");
+        assert_eq!(common_prefix(&["/stop".to_string(), "/st".to_string()]), "/st");
+        assert_eq!(common_prefix(&["ab".to_string()]), "ab");
+    }
+
+    #[test]
+    fn view_commands() {
+        assert_eq!(view_command("/view"), Some(None));
+        assert_eq!(view_command("/view 3 40"), Some(Some((3, 40))));
+        assert_eq!(view_command("/view 3"), None);
+        assert_eq!(view_command("/view -1 2"), None);
+        assert_eq!(view_command("/viewer"), None);
+    }
+
+    #[test]
+    fn clipping() {
+        let line = LogRecord::new(Level::Info, Category::Client, Some("1".to_string()), "日本語".to_string()).line();
+        assert_eq!(clip(&line, 100), line);
+        assert_eq!(strip_ansi(&clip(&line, 15)), "[CLIENT:1] 日本");
+        assert_eq!(strip_ansi(&clip(&line, 14)), "[CLIENT:1] 日");
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(Duration::from_millis(5900)), "5s");
+        assert_eq!(format_duration(Duration::from_secs(12 * 60 + 3)), "12m 03s");
+        assert_eq!(format_duration(Duration::from_secs(2 * 3600 + 5 * 60 + 59)), "2h 05m");
+    }
+}
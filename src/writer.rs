@@ -0,0 +1,161 @@
+use std::{io, sync::{Arc, Mutex}, thread::{self, JoinHandle}};
+
+use async_channel::{Sender, Receiver};
+use futures::executor::block_on;
+
+/// Where a writer thread puts what it receives. Files and the terminal can
+/// block, on the runtime thread that would hold up every connection.
+pub trait Sink<T>: Send + 'static {
+    fn write(&mut self, item: T) -> io::Result<()>;
+
+    /// Called whenever no more items are waiting.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Writes everything `receiver` gets to `sink` on a thread of its own, until
+/// the channel is closed and empty. The sink is flushed once it has caught up.
+/// Errors go to `on_error` once until writing works again.
+pub fn spawn<T: Send + 'static>(receiver: Receiver<T>, mut sink: impl Sink<T>, on_error: impl Fn(io::Error) + Send + 'static) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut failing = false;
+        while let Ok(item) = block_on(receiver.recv()) {
+            let mut written = sink.write(item);
+            if written.is_ok() && receiver.is_empty() {
+                written = sink.flush();
+            }
+            match written {
+                Ok(()) => failing = false,
+                Err(e) if !failing => {
+                    failing = true;
+                    on_error(e);
+                }
+                Err(_) => {}
+            }
+        }
+    })
+}
+
+/// A writer thread for files where every item counts: sending never waits
+/// and nothing is dropped as long as `finish` is awaited before exiting.
+pub struct Writer<T> {
+    sender: Sender<T>,
+    thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl<T: Send + 'static> Writer<T> {
+    pub fn new(sink: impl Sink<T>, on_error: impl Fn(io::Error) + Send + 'static) -> Self {
+        let (sender, receiver) = async_channel::unbounded();
+        let thread = spawn(receiver, sink, on_error);
+        Self { sender, thread: Arc::new(Mutex::new(Some(thread))) }
+    }
+
+    pub fn send(&self, item: T) {
+        let _ = self.sender.try_send(item);
+    }
+
+    /// Stops taking items and waits until the queued ones are written.
+    pub async fn finish(&self) {
+        self.sender.close();
+        let thread = self.thread.lock().unwrap().take();
+        if let Some(thread) = thread {
+            join(thread).await;
+        }
+    }
+}
+
+impl<T> Clone for Writer<T> {
+    fn clone(&self) -> Self {
+        Self { sender: self.sender.clone(), thread: self.thread.clone() }
+    }
+}
+
+/// Waits for a writer thread without blocking the runtime.
+pub async fn join(thread: JoinHandle<()>) {
+    let _ = tokio::task::spawn_blocking(move || thread.join()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Duration};
+
+    use super::*;
+    use crate::log::{LogRecord, Level, Category, Logger, LOG_CAPACITY};
+
+    /// Blocks in every write until it is dropped on the other end.
+    struct Stuck(mpsc::Receiver<()>);
+
+    impl Sink<LogRecord> for Stuck {
+        fn write(&mut self, _: LogRecord) -> io::Result<()> {
+            let _ = self.0.recv();
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn blocked_sinks_hold_up_nothing() {
+        let (release, stuck) = mpsc::channel();
+        let (sender, receiver) = async_channel::bounded(LOG_CAPACITY);
+        let thread = spawn(receiver, Stuck(stuck), |_| {});
+        let log = Logger::new(sender.clone());
+
+        let ticks = tokio::spawn(async {
+            let mut interval = tokio::time::interval(Duration::from_millis(1));
+            for _ in 0..10 {
+                interval.tick().await;
+            }
+        });
+        for i in 0..2 * LOG_CAPACITY {
+            log.log(LogRecord::new(Level::Info, Category::Server, None, i.to_string()));
+            tokio::task::yield_now().await;
+        }
+        tokio::time::timeout(Duration::from_secs(5), ticks).await
+            .expect("ticks stopped while the sink was blocked")
+            .unwrap();
+        // the queue filled up and the rest was dropped instead of waited for
+        assert_eq!(sender.len(), LOG_CAPACITY);
+
+        drop(release);
+        drop((log, sender));
+        thread.join().unwrap();
+    }
+
+    struct Collect(Arc<Mutex<Vec<u32>>>, bool);
+
+    impl Sink<u32> for Collect {
+        fn write(&mut self, item: u32) -> io::Result<()> {
+            self.0.lock().unwrap().push(item);
+            match self.1 {
+                true => Err(io::Error::other("disk full")),
+                false => Ok(()),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn finishing_writes_everything() {
+        let written = Arc::default();
+        let errors = Arc::new(Mutex::new(0));
+        let writer = Writer::new(Collect(Arc::clone(&written), true), {
+            let errors = errors.clone();
+            move |_| *errors.lock().unwrap() += 1
+        });
+        for item in 0..1000 {
+            writer.send(item);
+        }
+        writer.finish().await;
+        writer.send(1000);
+        writer.finish().await;
+
+        assert_eq!(*written.lock().unwrap(), (0..1000).collect::<Vec<_>>());
+        // reported once, not for every failed write
+        assert_eq!(*errors.lock().unwrap(), 1);
+    }
+}